@@ -1,23 +1,30 @@
-use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use spin::{Lazy, Mutex};
 use x2apic::ioapic::{IoApic, IrqMode, RedirectionTableEntry};
 use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerMode};
+use x86_64::instructions::interrupts;
 use x86_64::{instructions::port::Port, PhysAddr};
 
 use super::acpi::ACPI;
 use super::interrupts::InterruptIndex;
+use super::smp::{current_cpu, current_cpu_id};
 use crate::device::hpet::HPET;
 use crate::memory::convert_physical_to_virtual;
 
-const TIMER_FREQUENCY_HZ: u32 = 250;
+/// How long a thread runs before others waiting for its CPU get a turn.
+/// The timer is one-shot, so it only fires this often while they wait.
+pub const TIME_SLICE_NS: u64 = 4_000_000;
+
 const TIMER_CALIBRATION_ITERATION: u32 = 100;
 const IOAPIC_INTERRUPT_INDEX_OFFSET: u8 = 32;
 
 pub static APIC_INIT: AtomicBool = AtomicBool::new(false);
-pub static CALIBRATED_TIMER_INITIAL: AtomicU32 = AtomicU32::new(0);
+static TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
 
-pub static LAPIC: Lazy<Mutex<LocalApic>> = Lazy::new(|| unsafe {
+/// Enables the local APIC of this CPU, with its timer off, and hands it to
+/// the `CpuInfo` of the CPU.
+pub fn init_local() {
     let physical_address = PhysAddr::new(ACPI.apic.local_apic_address as u64);
     let virtual_address = convert_physical_to_virtual(physical_address);
 
@@ -31,10 +38,15 @@ pub static LAPIC: Lazy<Mutex<LocalApic>> = Lazy::new(|| unsafe {
         .build()
         .unwrap_or_else(|err| panic!("Failed to build local APIC: {:#?}", err));
 
-    lapic.enable();
+    unsafe { lapic.enable() };
+    current_cpu().set_local_apic(lapic);
+}
 
-    Mutex::new(lapic)
-});
+/// Runs `f` on the local APIC of this CPU. Every CPU only programs its own,
+/// so there is nothing to lock, but handlers must not reenter it.
+fn with_local_apic<T>(f: impl FnOnce(&mut LocalApic) -> T) -> T {
+    interrupts::without_interrupts(|| f(unsafe { current_cpu().local_apic() }))
+}
 
 pub static IOAPIC: Lazy<Mutex<IoApic>> = Lazy::new(|| unsafe {
     let physical_address = PhysAddr::new(ACPI.apic.io_apics[0].address as u64);
@@ -53,6 +65,7 @@ pub enum IrqVector {
 }
 
 pub fn init() {
+    init_local();
     unsafe {
         disable_pic();
         calibrate_timer();
@@ -65,11 +78,27 @@ pub fn init() {
     log::info!("APIC initialized successfully!");
 }
 
+/// Makes the timer of this CPU fire once at `deadline`, in HPET
+/// nanoseconds, or not at all.
+pub fn set_timer_deadline(deadline: Option<u64>) {
+    let initial = deadline.map_or(0, |deadline| {
+        let delay = deadline.saturating_sub(HPET.elapsed_ns());
+        let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::Relaxed);
+        // Far deadlines overflow 64 bits here. They are cut to the longest
+        // delay the timer takes, and rearmed when it fires.
+        let ticks = delay as u128 * ticks_per_ms as u128 / 1_000_000;
+        ticks.clamp(1, u32::MAX as u128) as u32
+    });
+    with_local_apic(|lapic| unsafe { lapic.set_timer_initial(initial) });
+}
+
 #[inline]
 pub fn end_of_interrupt() {
-    unsafe {
-        LAPIC.lock().end_of_interrupt();
-    }
+    with_local_apic(|lapic| unsafe { lapic.end_of_interrupt() });
+}
+
+pub fn send_ipi(vector: InterruptIndex, lapic_id: u32) {
+    with_local_apic(|lapic| unsafe { lapic.send_ipi(vector as u8, lapic_id) });
 }
 
 unsafe fn disable_pic() {
@@ -78,18 +107,17 @@ unsafe fn disable_pic() {
 }
 
 unsafe fn ioapic_add_entry(irq: IrqVector, vector: InterruptIndex) {
-    let lapic = LAPIC.lock();
     let mut ioapic = IOAPIC.lock();
     let mut entry = RedirectionTableEntry::default();
     entry.set_mode(IrqMode::Fixed);
-    entry.set_dest(lapic.id() as u8);
+    entry.set_dest(current_cpu_id() as u8);
     entry.set_vector(vector as u8);
     ioapic.set_table_entry(irq as u8, entry);
     ioapic.enable_irq(irq as u8);
 }
 
 pub unsafe fn calibrate_timer() {
-    let mut lapic = LAPIC.lock();
+    let lapic = current_cpu().local_apic();
     let mut lapic_total_ticks = 0;
 
     for _ in 0..TIMER_CALIBRATION_ITERATION {
@@ -100,10 +128,9 @@ pub unsafe fn calibrate_timer() {
     }
 
     let average_clock_per_ms = lapic_total_ticks / TIMER_CALIBRATION_ITERATION;
-    let calibrated_timer_initial = average_clock_per_ms * 1000 / TIMER_FREQUENCY_HZ;
-    log::debug!("Calibrated timer initial: {}", calibrated_timer_initial);
+    log::debug!("Calibrated timer ticks per ms: {}", average_clock_per_ms);
 
-    lapic.set_timer_mode(TimerMode::Periodic);
-    lapic.set_timer_initial(calibrated_timer_initial);
-    CALIBRATED_TIMER_INITIAL.store(calibrated_timer_initial, Ordering::SeqCst);
+    // Stays off until the scheduler asks for an interrupt.
+    lapic.set_timer_initial(0);
+    TIMER_TICKS_PER_MS.store(average_clock_per_ms as u64, Ordering::SeqCst);
 }
@@ -1,44 +1,91 @@
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
 use spin::Lazy;
+use x2apic::lapic::LocalApic;
 use x86_64::instructions::segmentation::{Segment, CS, SS};
 use x86_64::instructions::tables::load_tss;
+use x86_64::registers::model_specific::KernelGsBase;
 use x86_64::structures::gdt::GlobalDescriptorTable;
 use x86_64::structures::gdt::{Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{FrameUsage, KernelArea, MappingType, PcidCache};
 /*
 I just said that why zed was stucked.
 you see chat
 */
 
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
-const FAULT_STACK_SIZE: usize = 256;
-
+pub const NMI_IST_INDEX: usize = 1;
+pub const MACHINE_CHECK_IST_INDEX: usize = 2;
+const IST_STACK_COUNT: usize = 3;
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+/// Per-CPU state. `KernelGsBase` points at this struct so the syscall entry
+/// and the timer interrupt can find it after `swapgs`; the first three
+/// fields are read from assembly and must stay at offsets 0, 8 and 16.
+#[repr(C)]
 pub struct CpuInfo {
+    kernel_rsp: AtomicU64,
+    user_rsp: VirtAddr,
+    /// Cleared by the timer interrupt once it left the stack of the thread
+    /// switched away from, see `Thread::on_cpu`.
+    switched_out: AtomicPtr<AtomicBool>,
+    lapic_id: u32,
+    /// Dense index of the CPU, the bootstrap processor is 0.
+    index: usize,
     gdt: GlobalDescriptorTable,
-    tss: TaskStateSegment,
+    /// Only written by its own CPU, see `set_ring0_rsp`.
+    tss: UnsafeCell<TaskStateSegment>,
     selectors: Option<Selectors>,
-    fault_stack: [u8; FAULT_STACK_SIZE],
+    ist_stacks: Vec<KernelArea>,
+    /// Page table of the thread running here, 0 before the first one.
+    active_page_table: AtomicU64,
+    tlb_flush_requested: AtomicBool,
+    /// Set once the CPU takes interrupts, and with them TLB shootdowns.
+    online: AtomicBool,
+    pcids: PcidCache,
+    /// Only used by its own CPU, see `local_apic`.
+    local_apic: UnsafeCell<Option<LocalApic>>,
 }
 
 impl CpuInfo {
-    pub fn new() -> Self {
+    pub fn new(lapic_id: u32, index: usize) -> Self {
         Self {
+            kernel_rsp: AtomicU64::new(0),
+            user_rsp: VirtAddr::zero(),
+            switched_out: AtomicPtr::new(core::ptr::null_mut()),
+            lapic_id,
+            index,
             gdt: GlobalDescriptorTable::new(),
-            tss: TaskStateSegment::new(),
+            tss: UnsafeCell::new(TaskStateSegment::new()),
             selectors: None,
-            fault_stack: [0; FAULT_STACK_SIZE],
+            ist_stacks: Vec::new(),
+            active_page_table: AtomicU64::new(0),
+            tlb_flush_requested: AtomicBool::new(false),
+            online: AtomicBool::new(false),
+            pcids: PcidCache::new(),
+            local_apic: UnsafeCell::new(None),
         }
     }
 
     pub fn init(&mut self) {
         let (mut gdt, mut selectors) = COMMON_GDT.clone();
 
-        self.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = {
-            let stack_start = self.fault_stack.as_ptr() as u64;
-            VirtAddr::new(stack_start + self.fault_stack.len() as u64)
-        };
+        for index in 0..IST_STACK_COUNT {
+            let stack = KernelArea::new(
+                IST_STACK_SIZE,
+                MappingType::KernelData.flags(),
+                FrameUsage::KernelStack,
+            )
+            .expect("Failed to allocate interrupt stack");
+            self.tss.get_mut().interrupt_stack_table[index] = stack.end_address();
+            self.ist_stacks.push(stack);
+        }
 
-        let tss_ptr: *const _ = &self.tss;
+        let tss_ptr: *const _ = self.tss.get();
         let tss_selector = Some(gdt.append(Descriptor::tss_segment(unsafe { &*tss_ptr })));
         selectors.tss_selector = tss_selector;
 
@@ -56,13 +103,84 @@ impl CpuInfo {
             SS::set_reg(selectors.data_selector);
             load_tss(selectors.tss_selector.unwrap());
         }
+
+        KernelGsBase::write(VirtAddr::new(self as *const CpuInfo as u64));
+    }
+
+    pub fn lapic_id(&self) -> u32 {
+        self.lapic_id
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
     }
 
-    pub fn set_ring0_rsp(&mut self, rsp: VirtAddr) {
-        self.tss.privilege_stack_table[0] = rsp;
+    /// Must only be called on the CPU itself, with interrupts disabled.
+    pub fn set_ring0_rsp(&self, rsp: VirtAddr) {
+        unsafe { (*self.tss.get()).privilege_stack_table[0] = rsp };
+        self.kernel_rsp.store(rsp.as_u64(), Ordering::Relaxed);
+    }
+
+    /// The `on_cpu` flag to clear after switching stacks, if the switch
+    /// leaves a thread.
+    pub fn set_switched_out(&self, on_cpu: Option<&AtomicBool>) {
+        let on_cpu = on_cpu.map_or(core::ptr::null_mut(), |flag| flag as *const _ as *mut _);
+        self.switched_out.store(on_cpu, Ordering::Relaxed);
+    }
+
+    /// Must only be called on the CPU itself, before it takes interrupts.
+    pub fn set_local_apic(&self, local_apic: LocalApic) {
+        unsafe { *self.local_apic.get() = Some(local_apic) };
+    }
+
+    /// The registers of a local APIC only ever reach the one of the CPU
+    /// accessing them, so each CPU has a handle of its own.
+    ///
+    /// # Safety
+    /// Must only be called on the CPU itself, with interrupts disabled, and
+    /// the reference must be gone before they are enabled again.
+    pub unsafe fn local_apic(&self) -> &mut LocalApic {
+        (*self.local_apic.get())
+            .as_mut()
+            .expect("Local APIC not initialized")
+    }
+
+    pub fn active_page_table(&self) -> Option<PhysAddr> {
+        let address = self.active_page_table.load(Ordering::SeqCst);
+        (address != 0).then(|| PhysAddr::new(address))
+    }
+
+    /// Records the page table the CPU is about to switch to, before it does.
+    pub fn set_active_page_table(&self, page_table: PhysAddr) {
+        self.active_page_table
+            .store(page_table.as_u64(), Ordering::SeqCst);
+    }
+
+    pub fn pcids(&self) -> &PcidCache {
+        &self.pcids
+    }
+
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    pub fn request_tlb_flush(&self) {
+        self.tlb_flush_requested.store(true, Ordering::Release);
+    }
+
+    pub fn take_tlb_flush_request(&self) -> bool {
+        self.tlb_flush_requested.swap(false, Ordering::AcqRel)
     }
 }
 
+// The TSS and the local APIC are the only fields written through a shared
+// reference, and only by the CPU they belong to.
+unsafe impl Sync for CpuInfo {}
+
 static COMMON_GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
 
@@ -1,17 +1,25 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Lazy;
 //use x86_64::instructions::port::PortReadOnly;
 use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
-use super::gdt::DOUBLE_FAULT_IST_INDEX;
-use crate::arch::apic::LAPIC;
-use crate::task::SCHEDULER;
+use super::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use super::smp::current_cpu_id;
+use crate::device::serial::emergency_print;
+use crate::memory::{self, Access, TlbBatch};
+use crate::task::stack::KernelStack;
+use crate::task::{current_process, Exception, ExitReason, Thread, SCHEDULER};
 
 const INTERRUPT_INDEX_OFFSET: u8 = 32;
 
+/// NMIs taken so far. The handler can interrupt any lock holder, so it only
+/// counts them.
+static NMI_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -20,6 +28,13 @@ pub enum InterruptIndex {
     ApicSpurious,
     Keyboard,
     Mouse,
+    TlbShootdown,
+    /// Makes a CPU run the scheduler, sent by other CPUs that queued work
+    /// for it.
+    Reschedule,
+    /// Raised with `int` by threads that yield. Nothing is in service at
+    /// the local APIC then, so its handler must not signal the end of it.
+    Yield,
 }
 
 pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
@@ -33,15 +48,24 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
         .set_handler_fn(general_protection_fault);
 
     idt[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt);
+    idt[InterruptIndex::Reschedule as u8].set_handler_fn(timer_interrupt);
+    idt[InterruptIndex::Yield as u8].set_handler_fn(yield_interrupt);
     idt[InterruptIndex::ApicError as u8].set_handler_fn(lapic_error);
     idt[InterruptIndex::ApicSpurious as u8].set_handler_fn(spurious_interrupt);
     idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt);
     idt[InterruptIndex::Mouse as u8].set_handler_fn(mouse_interrupt);
+    idt[InterruptIndex::TlbShootdown as u8].set_handler_fn(tlb_shootdown_interrupt);
 
     unsafe {
         idt.double_fault
             .set_handler_fn(double_fault)
             .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt)
+            .set_stack_index(NMI_IST_INDEX as u16);
+        idt.machine_check
+            .set_handler_fn(machine_check)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX as u16);
     }
 
     return idt;
@@ -50,9 +74,8 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
 #[naked]
 extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
     fn timer_handler(context: VirtAddr) -> VirtAddr {
-        let context = SCHEDULER.lock().schedule(context);
         super::apic::end_of_interrupt();
-        context
+        SCHEDULER.schedule(context)
     }
 
     unsafe {
@@ -61,16 +84,32 @@ extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
             crate::push_context!(),
             "mov rdi, rsp",
             "call {timer_handler}",
-            "mov rsp, rax",
-            crate::pop_context!(),
-            "sti",
-            "iretq",
+            crate::switch_context!(),
             timer_handler = sym timer_handler,
             options(noreturn)
         );
     }
 }
 
+#[naked]
+extern "x86-interrupt" fn yield_interrupt(_frame: InterruptStackFrame) {
+    fn yield_handler(context: VirtAddr) -> VirtAddr {
+        SCHEDULER.schedule(context)
+    }
+
+    unsafe {
+        core::arch::asm!(
+            "cli",
+            crate::push_context!(),
+            "mov rdi, rsp",
+            "call {yield_handler}",
+            crate::switch_context!(),
+            yield_handler = sym yield_handler,
+            options(noreturn)
+        );
+    }
+}
+
 extern "x86-interrupt" fn lapic_error(_frame: InterruptStackFrame) {
     log::error!("Local APIC error!");
     super::apic::end_of_interrupt();
@@ -88,14 +127,33 @@ extern "x86-interrupt" fn segment_not_present(frame: InterruptStackFrame, error_
 }
 
 extern "x86-interrupt" fn general_protection_fault(frame: InterruptStackFrame, error_code: u64) {
+    if is_user_fault(&frame) {
+        log::warn!(
+            "Exception: General Protection Fault at {:#x}",
+            frame.instruction_pointer
+        );
+        log::warn!("Error Code: {:#x}", error_code);
+        kill_faulting_process(Exception::GeneralProtectionFault);
+    }
+
+    log::error!("Processor: {}", current_cpu_id());
     log::error!("Exception: General Protection Fault\n{:#?}", frame);
     log::error!("Error Code: {:#x}", error_code);
-    x86_64::instructions::hlt();
+    panic!("Unrecoverable fault occured, halting!");
 }
 
 extern "x86-interrupt" fn invalid_opcode(frame: InterruptStackFrame) {
+    if is_user_fault(&frame) {
+        log::warn!(
+            "Exception: Invalid Opcode at {:#x}",
+            frame.instruction_pointer
+        );
+        kill_faulting_process(Exception::InvalidOpcode);
+    }
+
+    log::error!("Processor: {}", current_cpu_id());
     log::error!("Exception: Invalid Opcode\n{:#?}", frame);
-    x86_64::instructions::hlt();
+    panic!("Unrecoverable fault occured, halting!");
 }
 
 extern "x86-interrupt" fn breakpoint(frame: InterruptStackFrame) {
@@ -103,11 +161,29 @@ extern "x86-interrupt" fn breakpoint(frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, error_code: u64) -> ! {
+    // Running off a kernel stack faults again while pushing the page fault.
+    check_stack_overflow();
     log::error!("Exception: Double Fault\n{:#?}", frame);
     log::error!("Error Code: {:#x}", error_code);
     panic!("Unrecoverable fault occured, halting!");
 }
 
+extern "x86-interrupt" fn non_maskable_interrupt(_frame: InterruptStackFrame) {
+    NMI_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+extern "x86-interrupt" fn machine_check(frame: InterruptStackFrame) -> ! {
+    // Like NMIs, machine checks arrive with any lock held, the logger's too.
+    emergency_print(format_args!(
+        "Processor: {}\nException: Machine Check\n{:#?}\n",
+        current_cpu_id(),
+        frame
+    ));
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
 extern "x86-interrupt" fn keyboard_interrupt(_frame: InterruptStackFrame) {
     //let scancode: u8 = unsafe { PortReadOnly::new(0x60).read() };
     //crate::device::keyboard::add_scancode(scancode);
@@ -120,17 +196,84 @@ extern "x86-interrupt" fn mouse_interrupt(_frame: InterruptStackFrame) {
     super::apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    log::warn!("Processor: {}", unsafe { LAPIC.lock().id() });
-    log::warn!("Exception: Page Fault\n{:#?}", frame);
-    log::warn!("Error Code: {:#?}", error_code);
-    match Cr2::read() {
+extern "x86-interrupt" fn tlb_shootdown_interrupt(_frame: InterruptStackFrame) {
+    let cpu_id = current_cpu_id();
+    memory::handle_shootdown(cpu_id);
+    super::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn page_fault(
+    mut frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let address = Cr2::read();
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Access::Execute
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Access::Write
+    } else {
+        Access::Read
+    };
+
+    if is_user_fault(&frame) {
+        if let Ok(address) = address {
+            let mut batch = TlbBatch::new();
+            let result = current_process()
+                .write()
+                .handle_page_fault(address, access, &mut batch);
+            batch.flush();
+            if result.is_ok() {
+                return;
+            }
+        }
+
+        log::warn!("Segmentation fault at {:#x}", frame.instruction_pointer);
+        log::warn!(
+            "Error Code: {:#?}, Fault Address: {:?}",
+            error_code,
+            address
+        );
+        kill_faulting_process(Exception::PageFault);
+    }
+
+    if let Ok(address) = address {
+        if super::user::handle_copy_fault(&mut frame, address, access) {
+            return;
+        }
+    }
+
+    check_stack_overflow();
+    log::error!("Processor: {}", current_cpu_id());
+    log::error!("Exception: Page Fault\n{:#?}", frame);
+    log::error!("Error Code: {:#?}", error_code);
+    match address {
         Ok(address) => {
-            log::warn!("Fault Address: {:#x}", address);
+            log::error!("Fault Address: {:#x}", address);
         }
         Err(error) => {
-            log::warn!("Invalid virtual address: {:?}", error);
+            log::error!("Invalid virtual address: {:?}", error);
         }
     }
-    x86_64::instructions::hlt();
+    panic!("Unrecoverable fault occured, halting!");
+}
+
+fn check_stack_overflow() {
+    let address = Cr2::read().ok();
+    if let Some(thread) = address.and_then(KernelStack::overflowed_thread) {
+        panic!("Kernel stack overflow in thread {}", thread.0);
+    }
+}
+
+#[inline]
+fn is_user_fault(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Faults raised in user mode only take down the faulting process.
+fn kill_faulting_process(exception: Exception) -> ! {
+    let process = current_process();
+    log::warn!("Process {} killed by {:?}", process.read().id.0, exception);
+    process.write().kill(ExitReason::Exception(exception));
+    drop(process);
+    Thread::exit_current()
 }
@@ -1,10 +1,10 @@
-use apic::{APIC_INIT, CALIBRATED_TIMER_INITIAL, LAPIC};
+use apic::APIC_INIT;
 use core::sync::atomic::Ordering;
 use interrupts::IDT;
 use limine::smp::Cpu;
 use smp::CPUS;
 
-use crate::task::SCHEDULER_INIT;
+use crate::task::{Thread, SCHEDULER_INIT};
 
 pub mod acpi;
 pub mod apic;
@@ -16,21 +16,18 @@ pub mod user;
 unsafe extern "C" fn ap_entry(smp_info: &Cpu) -> ! {
     CPUS.write().get(smp_info.lapic_id).load();
     IDT.load();
+    crate::memory::init_pcid();
 
     while !APIC_INIT.load(Ordering::SeqCst) {}
-    LAPIC.lock().enable();
-
-    let timer_initial = CALIBRATED_TIMER_INITIAL.load(Ordering::SeqCst);
-    LAPIC.lock().set_timer_initial(timer_initial);
+    apic::init_local();
 
     crate::syscall::init();
 
     while !SCHEDULER_INIT.load(Ordering::SeqCst) {}
-    x86_64::instructions::interrupts::enable();
+    crate::task::start_scheduling();
 
     log::debug!("Application Processor {} started", smp_info.id);
 
-    loop {
-        x86_64::instructions::hlt();
-    }
+    // The idle thread takes over from here.
+    Thread::exit_current()
 }
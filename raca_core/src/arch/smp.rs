@@ -3,6 +3,7 @@ use alloc::collections::BTreeMap;
 use limine::request::SmpRequest;
 use limine::response::SmpResponse;
 use spin::{Lazy, RwLock};
+use x86_64::registers::model_specific::KernelGsBase;
 
 use super::ap_entry;
 use super::gdt::CpuInfo;
@@ -34,7 +35,8 @@ impl Cpus {
 impl Cpus {
     pub fn new() -> Self {
         let mut cpus = BTreeMap::new();
-        cpus.insert(*BSP_LAPIC_ID, Box::leak(Box::new(CpuInfo::new())));
+        let bsp_info = CpuInfo::new(*BSP_LAPIC_ID, 0);
+        cpus.insert(*BSP_LAPIC_ID, Box::leak(Box::new(bsp_info)));
         Cpus(cpus)
     }
 
@@ -49,10 +51,29 @@ impl Cpus {
             if cpu.id == *BSP_LAPIC_ID {
                 continue;
             }
-            let info = Box::leak(Box::new(CpuInfo::new()));
+            let info = Box::leak(Box::new(CpuInfo::new(cpu.lapic_id, self.0.len())));
             info.init();
             self.0.insert(cpu.lapic_id, info);
             cpu.goto_address.write(ap_entry);
         }
     }
 }
+
+/// The `CpuInfo` of the running CPU, which `KernelGsBase` points at, without
+/// locking `CPUS`. Only valid once the CPU loaded its `CpuInfo`.
+pub fn current_cpu() -> &'static CpuInfo {
+    unsafe { &*KernelGsBase::read().as_ptr::<CpuInfo>() }
+}
+
+/// The LAPIC id of the running CPU, read from its `CpuInfo` rather than from
+/// the local APIC registers.
+pub fn current_cpu_id() -> u32 {
+    current_cpu().lapic_id()
+}
+
+/// The index of the running CPU from its `CpuInfo`, or `None` before the CPU
+/// loaded it. Allocators run that early, while `CpuInfo`s are being set up.
+pub fn current_cpu_index() -> Option<usize> {
+    let info = KernelGsBase::read();
+    (!info.is_null()).then(|| unsafe { (*info.as_ptr::<CpuInfo>()).index() })
+}
@@ -1,7 +1,16 @@
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::arch::global_asm;
 use core::fmt::{Debug, Formatter};
 use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+use x86_64::structures::paging::{OffsetPageTable, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::memory::{Access, TlbBatch, USER_END};
+use crate::task::current_process;
 
 #[repr(C)]
 pub struct UserPtr<T, P: Policy> {
@@ -9,16 +18,25 @@ pub struct UserPtr<T, P: Policy> {
     mark: PhantomData<P>,
 }
 
-pub trait Policy {}
+pub trait Policy {
+    /// Whether the memory has to be mapped writable.
+    const WRITABLE: bool;
+}
 pub trait Read: Policy {}
 pub trait Write: Policy {}
 pub enum In {}
 pub enum Out {}
 pub enum InOut {}
 
-impl Policy for In {}
-impl Policy for Out {}
-impl Policy for InOut {}
+impl Policy for In {
+    const WRITABLE: bool = false;
+}
+impl Policy for Out {
+    const WRITABLE: bool = true;
+}
+impl Policy for InOut {
+    const WRITABLE: bool = true;
+}
 impl Read for In {}
 impl Write for Out {}
 impl Read for InOut {}
@@ -83,25 +101,150 @@ impl<T, P: Policy> UserPtr<T, P> {
     }
 
     pub fn check(&self) -> Result<()> {
+        self.check_array(1)
+    }
+
+    /// Checks that `len` elements starting at the pointer lie in user space
+    /// and are mapped with the permissions the policy needs.
+    pub fn check_array(&self, len: usize) -> Result<()> {
         if self.ptr.is_null() {
             return Err(Error::InvalidPointer);
         }
         if (self.ptr as usize) % core::mem::align_of::<T>() != 0 {
             return Err(Error::InvalidPointer);
         }
-        Ok(())
+        let size = len
+            .checked_mul(core::mem::size_of::<T>())
+            .ok_or(Error::InvalidLength)?;
+        check_user_range::<P>(self.ptr as usize, size)
     }
 }
 
-impl<T, P: Read> UserPtr<T, P> {
-    pub fn as_ref(&self) -> Result<&'static T> {
-        Ok(unsafe { &*self.ptr })
+// Copies `rdx` bytes from `rsi` to `rdi`, returning 0, or 1 if a page
+// fault on user memory could not be resolved on the way. Only the
+// `rep movsb` touches user memory, see `handle_copy_fault`.
+global_asm!(
+    ".global raca_user_copy",
+    "raca_user_copy:",
+    "mov rcx, rdx",
+    ".global raca_user_copy_access",
+    "raca_user_copy_access:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    ".global raca_user_copy_fixup",
+    "raca_user_copy_fixup:",
+    "mov eax, 1",
+    "ret",
+);
+
+extern "C" {
+    fn raca_user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn raca_user_copy_access();
+    fn raca_user_copy_fixup();
+}
+
+/// Copies between kernel and user memory. The user range must have been
+/// checked, but another thread of the process may unmap it meanwhile, in
+/// which case the copy fails instead of faulting.
+unsafe fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> Result<()> {
+    match raca_user_copy(dst, src, len) {
+        0 => Ok(()),
+        _ => Err(Error::InvalidPointer),
+    }
+}
+
+/// Resolves a page fault raised in kernel mode by a user copy at `address`,
+/// like a fault in user mode would be, or makes the copy fail. Returns
+/// whether the fault came from a copy.
+pub fn handle_copy_fault(
+    frame: &mut InterruptStackFrame,
+    address: VirtAddr,
+    access: Access,
+) -> bool {
+    let copy_access = raca_user_copy_access as *const () as u64;
+    if frame.instruction_pointer.as_u64() != copy_access || address.as_u64() >= USER_END {
+        return false;
+    }
+
+    // Copies never run with the process locked, they check the range first.
+    let mut batch = TlbBatch::new();
+    let result = current_process()
+        .write()
+        .handle_page_fault(address, access, &mut batch);
+    batch.flush();
+
+    if result.is_err() {
+        let fixup = VirtAddr::new(raca_user_copy_fixup as *const () as u64);
+        unsafe {
+            frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = fixup)
+        };
+    }
+    true
+}
+
+fn is_accessible(page_table: &OffsetPageTable, page: Page, required: PageTableFlags) -> bool {
+    matches!(
+        page_table.translate(page.start_address()),
+        TranslateResult::Mapped { flags, .. } if flags.contains(required)
+    )
+}
+
+fn check_user_range<P: Policy>(start: usize, size: usize) -> Result<()> {
+    if size == 0 {
+        return Ok(());
+    }
+    let end = start.checked_add(size).ok_or(Error::InvalidPointer)?;
+    if end as u64 > USER_END {
+        return Err(Error::InvalidPointer);
     }
 
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if P::WRITABLE {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let access = if P::WRITABLE {
+        Access::Write
+    } else {
+        Access::Read
+    };
+
+    // Declared first so that it is flushed after the process is unlocked.
+    let mut batch = TlbBatch::new();
+    let process = current_process();
+    let mut process = process.write();
+    let start_page: Page = Page::containing_address(VirtAddr::new(start as u64));
+    let end_page = Page::containing_address(VirtAddr::new(end as u64 - 1));
+    for page in Page::range_inclusive(start_page, end_page) {
+        if is_accessible(&process.page_table, page, required) {
+            continue;
+        }
+        // Not touched yet or copy-on-write, fault it in like user mode would.
+        process
+            .handle_page_fault(page.start_address(), access, &mut batch)
+            .map_err(|_| Error::InvalidPointer)?;
+        if !is_accessible(&process.page_table, page, required) {
+            return Err(Error::InvalidPointer);
+        }
+    }
+    Ok(())
+}
+
+impl<T, P: Read> UserPtr<T, P> {
     pub fn read(&self) -> Result<T> {
-        // TODO: check ptr and return err
         self.check()?;
-        Ok(unsafe { self.ptr.read() })
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            user_copy(
+                value.as_mut_ptr() as *mut u8,
+                self.ptr as *const u8,
+                core::mem::size_of::<T>(),
+            )?;
+            Ok(value.assume_init())
+        }
     }
 
     pub fn read_if_not_null(&self) -> Result<Option<T>> {
@@ -116,39 +259,61 @@ impl<T, P: Read> UserPtr<T, P> {
         if len == 0 {
             return Ok(Vec::default());
         }
-        self.check()?;
+        self.check_array(len)?;
         let mut ret = Vec::<T>::with_capacity(len);
         unsafe {
+            user_copy(
+                ret.as_mut_ptr() as *mut u8,
+                self.ptr as *const u8,
+                len * core::mem::size_of::<T>(),
+            )?;
             ret.set_len(len);
-            ret.as_mut_ptr().copy_from_nonoverlapping(self.ptr, len);
         }
         Ok(ret)
     }
 }
 
+impl<T, P: Read> UserPtr<T, P> {
+    /// Counts the elements before the first all-zero one, checking every
+    /// page before touching it.
+    fn find_null(&self) -> Result<usize> {
+        let size = core::mem::size_of::<T>();
+        let mut checked_end = 0;
+        let mut bytes = Vec::<u8>::with_capacity(size);
+        for index in 0.. {
+            let element = self.add(index);
+            let end = element.ptr as usize + size;
+            if end > checked_end {
+                element.check()?;
+                checked_end = ((end - 1) & !0xfff) + 0x1000;
+            }
+            unsafe {
+                user_copy(bytes.as_mut_ptr(), element.ptr as *const u8, size)?;
+                bytes.set_len(size);
+            }
+            if bytes.iter().all(|&byte| byte == 0) {
+                return Ok(index);
+            }
+        }
+        unreachable!()
+    }
+}
+
 impl<P: Read> UserPtr<u8, P> {
     pub fn read_string(&self, len: usize) -> Result<String> {
-        self.check()?;
-        let src = unsafe { core::slice::from_raw_parts(self.ptr, len) };
-        let s = core::str::from_utf8(src).map_err(|_| Error::InvalidUtf8)?;
-        Ok(String::from(s))
+        let bytes = self.read_array(len)?;
+        String::from_utf8(bytes).map_err(|_| Error::InvalidUtf8)
     }
 
     pub fn read_cstring(&self) -> Result<String> {
-        self.check()?;
-        let len = unsafe { (0usize..).find(|&i| *self.ptr.add(i) == 0).unwrap() };
+        let len = self.find_null()?;
         self.read_string(len)
     }
 }
 
 impl<P: Read> UserPtr<UserPtr<u8, P>, P> {
     pub fn read_cstring_array(&self) -> Result<Vec<String>> {
-        self.check()?;
-        let len = unsafe {
-            (0usize..)
-                .find(|&i| self.ptr.add(i).read().is_null())
-                .unwrap()
-        };
+        let len = self.find_null()?;
         self.read_array(len)?
             .into_iter()
             .map(|ptr| ptr.read_cstring())
@@ -159,10 +324,15 @@ impl<P: Read> UserPtr<UserPtr<u8, P>, P> {
 impl<T, P: Write> UserPtr<T, P> {
     pub fn write(&mut self, value: T) -> Result<()> {
         self.check()?;
+        // The value now lives in user memory.
+        let value = core::mem::ManuallyDrop::new(value);
         unsafe {
-            self.ptr.write(value);
+            user_copy(
+                self.ptr as *mut u8,
+                &*value as *const T as *const u8,
+                core::mem::size_of::<T>(),
+            )
         }
-        Ok(())
     }
 
     pub fn write_if_not_null(&mut self, value: T) -> Result<()> {
@@ -176,22 +346,22 @@ impl<T, P: Write> UserPtr<T, P> {
         if values.is_empty() {
             return Ok(());
         }
-        self.check()?;
+        self.check_array(values.len())?;
         unsafe {
-            self.ptr
-                .copy_from_nonoverlapping(values.as_ptr(), values.len());
+            user_copy(
+                self.ptr as *mut u8,
+                values.as_ptr() as *const u8,
+                core::mem::size_of_val(values),
+            )
         }
-        Ok(())
     }
 }
 
 impl<P: Write> UserPtr<u8, P> {
     pub fn write_cstring(&mut self, s: &str) -> Result<()> {
         let bytes = s.as_bytes();
+        self.check_array(bytes.len() + 1)?;
         self.write_array(bytes)?;
-        unsafe {
-            self.ptr.add(bytes.len()).write(0);
-        }
-        Ok(())
+        self.add(bytes.len()).write(0)
     }
 }
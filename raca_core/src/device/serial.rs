@@ -21,6 +21,13 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Writes without taking `SERIAL`, for handlers that can interrupt its
+/// holder. The output may interleave with what the holder writes.
+pub fn emergency_print(args: fmt::Arguments) {
+    let mut serial_port = unsafe { SerialPort::new(0x3f8) };
+    let _ = serial_port.write_fmt(args);
+}
+
 pub static SERIAL: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
     let mut serial_port = unsafe { SerialPort::new(0x3f8) };
     serial_port.init();
@@ -12,17 +12,23 @@ pub mod device;
 pub mod error;
 pub mod memory;
 pub mod module;
+pub mod object;
+#[cfg(feature = "self-test")]
+mod selftest;
 pub mod syscall;
 pub mod task;
 
 pub fn init() {
-    memory::init_heap();
+    memory::init();
     device::log::init();
     arch::smp::CPUS.write().init_bsp();
+    memory::init_pcid();
     arch::interrupts::IDT.load();
     arch::smp::CPUS.write().init_ap();
     arch::apic::init();
     syscall::init();
     task::init();
+    #[cfg(feature = "self-test")]
+    selftest::run();
     log::info!("racaOS intialization completed!");
 }
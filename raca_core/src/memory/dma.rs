@@ -1,27 +1,230 @@
-use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
-use x86_64::structures::paging::{PageSize, Size4KiB};
-use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+use core::arch::x86_64::_mm_clflush;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{OffsetPageTable, Page, PageTable, PageTableFlags};
+use x86_64::structures::paging::{PageSize, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
 
-use crate::memory::FRAME_ALLOCATOR;
-use crate::memory::{convert_physical_to_virtual, convert_virtual_to_physical};
+use crate::error::{RcError, RcResult};
+use crate::memory::{convert_physical_to_virtual, order_of, BuddyFrameAllocator};
+use crate::memory::{FrameUsage, TlbBatch, Zone, FRAME_ALLOCATOR, KERNEL_PAGE_TABLE, MAX_ORDER};
 
-pub struct DmaMemoryManager;
+const CACHE_LINE_SIZE: usize = 64;
 
-impl DmaMemoryManager {
-    pub const UNIT_SIZE: usize = Size4KiB::SIZE as usize;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCache {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+}
+
+impl DmaCache {
+    const FLAGS: PageTableFlags = PageTableFlags::WRITE_THROUGH.union(PageTableFlags::NO_CACHE);
+
+    fn page_table_flags(&self) -> PageTableFlags {
+        match self {
+            Self::WriteBack => PageTableFlags::empty(),
+            Self::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            Self::Uncached => Self::FLAGS,
+        }
+    }
+}
 
-    pub fn allocate() -> (PhysAddr, VirtAddr) {
-        let physical_address = FRAME_ALLOCATOR.lock().allocate_frame().unwrap();
-        let physical_address = physical_address.start_address();
-        let virtual_address = convert_physical_to_virtual(physical_address);
-        (physical_address, virtual_address)
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+    /// `Zone::Dma32` for devices that can only address 32 bits.
+    pub zone: Zone,
+    /// Alignment of the physical address, a power of two no larger than
+    /// `DmaBuffer::MAX_ALIGNMENT`.
+    pub alignment: usize,
+    pub cache: DmaCache,
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self {
+            zone: Zone::Normal,
+            alignment: DmaBuffer::UNIT_SIZE,
+            cache: DmaCache::WriteBack,
+        }
     }
+}
 
-    pub fn deallocate(virtual_address: VirtAddr) {
-        let physical_address = convert_virtual_to_physical(virtual_address);
-        let physical_address = PhysFrame::containing_address(physical_address);
+/// A zeroed, physically contiguous buffer, freed when dropped.
+pub struct DmaBuffer {
+    physical_address: PhysAddr,
+    virtual_address: VirtAddr,
+    frame_count: usize,
+    cache: DmaCache,
+}
+
+impl DmaBuffer {
+    pub const UNIT_SIZE: usize = Size4KiB::SIZE as usize;
+    /// The size of the largest block the frame allocator hands out.
+    pub const MAX_ALIGNMENT: usize = Self::UNIT_SIZE << MAX_ORDER;
+
+    pub fn new(size: usize, constraints: DmaConstraints) -> RcResult<Self> {
+        let alignment = constraints.alignment;
+        if size == 0 || !alignment.is_power_of_two() || alignment > Self::MAX_ALIGNMENT {
+            return Err(RcError::INVALID_ARGS);
+        }
+        let frame_count = size.div_ceil(Self::UNIT_SIZE);
+        let align_order = order_of(alignment.div_ceil(Self::UNIT_SIZE));
+
+        let frame = interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR.lock().allocate_contiguous(
+                frame_count,
+                align_order,
+                constraints.zone,
+                FrameUsage::Dma,
+            )
+        })
+        .ok_or(RcError::NO_MEMORY)?;
+
+        let physical_address = frame.start_address();
+        let direct_address = convert_physical_to_virtual(physical_address);
+        let buffer_size = frame_count * Self::UNIT_SIZE;
         unsafe {
-            FRAME_ALLOCATOR.lock().deallocate_frame(physical_address);
+            direct_address
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, buffer_size)
+        };
+
+        let buffer = Self {
+            physical_address,
+            virtual_address: direct_address,
+            frame_count,
+            cache: constraints.cache,
+        };
+
+        // Buffers that are not write-back change the cache type of their
+        // pages in the direct map, as mapping a frame with two types at once
+        // is undefined.
+        if constraints.cache != DmaCache::WriteBack {
+            buffer.set_cache(constraints.cache)?;
+            // Nothing written while the pages were write-back may linger.
+            for offset in (0..buffer_size).step_by(CACHE_LINE_SIZE) {
+                unsafe { _mm_clflush(direct_address.as_ptr::<u8>().add(offset)) };
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start_page = Page::containing_address(self.virtual_address);
+        (0..self.frame_count as u64).map(move |index| start_page + index)
+    }
+
+    fn set_cache(&self, cache: DmaCache) -> RcResult<()> {
+        let mut batch = TlbBatch::kernel();
+        let result = interrupts::without_interrupts(|| {
+            let mut page_table = KERNEL_PAGE_TABLE.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            for page in self.pages() {
+                let entry = direct_map_entry(&mut page_table, page, &mut frame_allocator)?;
+                let flags = (entry.flags() - DmaCache::FLAGS) | cache.page_table_flags();
+                entry.set_flags(flags);
+                batch.add(&page_table, page);
+            }
+            Ok(())
+        });
+        batch.flush();
+        result
+    }
+
+    pub fn physical_address(&self) -> PhysAddr {
+        self.physical_address
+    }
+
+    pub fn virtual_address(&self) -> VirtAddr {
+        self.virtual_address
+    }
+
+    pub fn size(&self) -> usize {
+        self.frame_count * Self::UNIT_SIZE
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virtual_address.as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.size()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if self.cache != DmaCache::WriteBack {
+            // Only fails for pages it never got to change.
+            let _ = self.set_cache(DmaCache::WriteBack);
         }
+
+        let frame = PhysFrame::containing_address(self.physical_address);
+        interrupts::without_interrupts(|| unsafe {
+            FRAME_ALLOCATOR
+                .lock()
+                .deallocate_contiguous(frame, self.frame_count);
+        });
     }
 }
+
+/// The 4 KiB entry mapping `page` of the direct map, splitting the huge
+/// pages the bootloader may have mapped it with.
+fn direct_map_entry<'a>(
+    page_table: &'a mut OffsetPageTable<'static>,
+    page: Page,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> RcResult<&'a mut PageTableEntry> {
+    let mut table = page_table.level_4_table_mut();
+    let indices = [page.p4_index(), page.p3_index(), page.p2_index()];
+    for (level, index) in (2..=4).rev().zip(indices) {
+        let entry = &mut table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(RcError::NOT_FOUND);
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            split_huge_page(entry, level, frame_allocator)?;
+        }
+        let next = convert_physical_to_virtual(entry.addr());
+        table = unsafe { &mut *next.as_mut_ptr::<PageTable>() };
+    }
+
+    let entry = &mut table[page.p1_index()];
+    match entry.flags().contains(PageTableFlags::PRESENT) {
+        true => Ok(entry),
+        false => Err(RcError::NOT_FOUND),
+    }
+}
+
+/// Replaces the huge page of a level 3 or 2 entry by a table mapping the
+/// same memory with the same flags in pages one level smaller.
+fn split_huge_page(
+    entry: &mut PageTableEntry,
+    level: usize,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> RcResult<()> {
+    let (page_size, flags) = match level {
+        3 => (Size2MiB::SIZE, entry.flags()),
+        _ => (Size4KiB::SIZE, entry.flags() - PageTableFlags::HUGE_PAGE),
+    };
+    // Bit 12 is the PAT bit of huge pages, not part of the address.
+    let start = entry.addr().align_down(page_size * 512);
+
+    let frame = frame_allocator
+        .allocate_for(FrameUsage::PageTable)
+        .ok_or(RcError::NO_MEMORY)?;
+    let table = convert_physical_to_virtual(frame.start_address());
+    let table = unsafe { &mut *table.as_mut_ptr::<PageTable>() };
+    for (index, child) in table.iter_mut().enumerate() {
+        child.set_addr(start + index as u64 * page_size, flags);
+    }
+
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    Ok(())
+}
@@ -1,135 +1,421 @@
-use bit_field::BitField;
+use core::sync::atomic::{AtomicU32, Ordering};
+
 use limine::memory_map::EntryType;
 use limine::response::MemoryMapResponse;
-use x86_64::structures::paging::{FrameAllocator, PhysFrame};
-use x86_64::structures::paging::{FrameDeallocator, Size4KiB};
+use spin::Once;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::structures::paging::{PageSize, Size4KiB};
 use x86_64::PhysAddr;
 
 use crate::memory::convert_physical_to_virtual;
 
-pub struct Bitmap {
-    inner: &'static mut [u8],
+const FRAME_SIZE: usize = Size4KiB::SIZE as usize;
+
+/// Largest block order, a block of this order is a 1 GiB frame.
+pub const MAX_ORDER: usize = 18;
+
+/// First frame above the DMA32 zone.
+const DMA32_END: usize = (4 << 30) / FRAME_SIZE;
+
+/// Marks the first frame of a free block in `orders`, the low bits hold
+/// the order of the block.
+const FREE_BLOCK: u8 = 0x80;
+/// Marks the first frame of an allocated block in `orders`, the low bits
+/// hold its `FrameUsage`.
+const ALLOCATED_BLOCK: u8 = 0x40;
+const NO_FRAME: usize = usize::MAX;
+
+/// Extra owners of frames shared copy-on-write, one counter per physical
+/// frame, zero for frames with a single owner. Allocated at boot next to
+/// `orders` so that nothing here touches the heap, which grows by taking the
+/// frame allocator.
+static SHARED_FRAMES: Once<&'static [AtomicU32]> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 4 GiB, for devices that can only address 32 bits.
+    Dma32,
+    /// Anywhere. High memory is used first to keep DMA32 free.
+    Normal,
 }
 
-impl Bitmap {
-    pub fn new(inner: &'static mut [u8]) -> Self {
-        inner.fill(0);
-        Self { inner }
-    }
+impl Zone {
+    const COUNT: usize = 2;
 
-    pub fn len(&self) -> usize {
-        self.inner.len()
+    fn of(index: usize) -> Self {
+        if index < DMA32_END {
+            Self::Dma32
+        } else {
+            Self::Normal
+        }
     }
 
-    pub fn get(&self, index: usize) -> bool {
-        let byte = self.inner[index / 8];
-        byte.get_bit(index % 8)
+    /// Zones an allocation may come from, in the order they are tried.
+    fn fallbacks(self) -> &'static [Zone] {
+        match self {
+            Self::Dma32 => &[Self::Dma32],
+            Self::Normal => &[Self::Normal, Self::Dma32],
+        }
     }
+}
 
-    pub fn set(&mut self, index: usize, value: bool) {
-        let byte = &mut self.inner[index / 8];
-        byte.set_bit(index % 8, value);
-    }
+/// What allocated frames are used for, counted for memory statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameUsage {
+    KernelHeap,
+    Slab,
+    KernelStack,
+    Module,
+    PageTable,
+    User,
+    Dma,
+    /// Anything the kernel allocates that fits none of the above.
+    Other,
 }
 
-pub struct BitmapFrameAllocator {
-    bitmap: Bitmap,
-    usable_frames: usize,
-    next_frame: usize,
+impl FrameUsage {
+    pub const COUNT: usize = 8;
 }
 
-impl BitmapFrameAllocator {
-    pub fn init(memory_map: &MemoryMapResponse) -> Self {
-        let memory_size = memory_map
-            .entries()
-            .last()
-            .map(|region| region.base + region.length)
-            .expect("No memory regions found");
+/// Physical memory by what it is used for, in bytes. Shared with user space
+/// by the `mem_info` syscall.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStats {
+    pub total: u64,
+    pub free: u64,
+    pub kernel_heap: u64,
+    pub slab: u64,
+    pub kernel_stacks: u64,
+    pub modules: u64,
+    pub page_tables: u64,
+    pub user: u64,
+    pub dma: u64,
+    pub other: u64,
+}
 
-        let bitmap_size = (memory_size / 4096).div_ceil(8) as usize;
+/// Free list links, stored in the first bytes of each free block.
+#[derive(Clone, Copy)]
+struct FreeLink {
+    prev: usize,
+    next: usize,
+}
+
+/// The smallest order whose blocks hold `frame_count` frames.
+pub fn order_of(frame_count: usize) -> usize {
+    frame_count.max(1).next_power_of_two().trailing_zeros() as usize
+}
 
+fn order_of_size<S: PageSize>() -> usize {
+    order_of((S::SIZE as usize) / FRAME_SIZE)
+}
+
+pub struct BuddyFrameAllocator {
+    /// One byte per physical frame, see `FREE_BLOCK`.
+    orders: &'static mut [u8],
+    free_lists: [[usize; MAX_ORDER + 1]; Zone::COUNT],
+    free_frames: [usize; Zone::COUNT],
+    used_frames: [usize; FrameUsage::COUNT],
+    total_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    pub fn init(memory_map: &MemoryMapResponse) -> Self {
         let usable_regions = memory_map
             .entries()
             .iter()
             .filter(|region| region.entry_type == EntryType::USABLE);
 
-        let bitmap_address = usable_regions
+        let frame_count = usable_regions
             .clone()
-            .find(|region| region.length >= bitmap_size as u64)
+            .map(|region| ((region.base + region.length) as usize) / FRAME_SIZE)
+            .max()
+            .expect("No memory regions found");
+
+        let shared_offset = frame_count.next_multiple_of(size_of::<AtomicU32>());
+        let metadata_size = shared_offset + frame_count * size_of::<AtomicU32>();
+
+        let orders_address = usable_regions
+            .clone()
+            .find(|region| region.length as usize >= metadata_size)
             .map(|region| region.base)
-            .expect("No suitable memory region for bitmap");
+            .expect("No suitable memory region for frame orders");
 
-        let bitmap_buffer = unsafe {
-            let physical_address = PhysAddr::new(bitmap_address);
+        let orders = unsafe {
+            let physical_address = PhysAddr::new(orders_address);
             let virtual_address = convert_physical_to_virtual(physical_address).as_u64();
-            core::slice::from_raw_parts_mut(virtual_address as *mut u8, bitmap_size)
+            core::slice::from_raw_parts_mut(virtual_address as *mut u8, frame_count)
         };
+        orders.fill(0);
 
-        let mut bitmap = Bitmap::new(bitmap_buffer);
-        let mut usable_frames = 0;
-        let mut next_frame = usize::MAX;
+        SHARED_FRAMES.call_once(|| unsafe {
+            let physical_address = PhysAddr::new(orders_address + shared_offset as u64);
+            let virtual_address = convert_physical_to_virtual(physical_address).as_u64();
+            let shared = virtual_address as *mut AtomicU32;
+            for index in 0..frame_count {
+                shared.add(index).write(AtomicU32::new(0));
+            }
+            core::slice::from_raw_parts(shared, frame_count)
+        });
 
-        for region in usable_regions {
-            let start_page_index = (region.base / 4096) as usize;
-            let frame_count = (region.length / 4096) as usize;
+        let mut allocator = BuddyFrameAllocator {
+            orders,
+            free_lists: [[NO_FRAME; MAX_ORDER + 1]; Zone::COUNT],
+            free_frames: [0; Zone::COUNT],
+            used_frames: [0; FrameUsage::COUNT],
+            total_frames: 0,
+        };
 
-            usable_frames += frame_count;
-            next_frame = next_frame.min(start_page_index);
+        let orders_start = orders_address as usize / FRAME_SIZE;
+        let orders_end = orders_start + metadata_size.div_ceil(FRAME_SIZE);
 
-            for index in start_page_index..start_page_index + frame_count {
-                bitmap.set(index, true);
+        for region in usable_regions {
+            let start = (region.base as usize).div_ceil(FRAME_SIZE);
+            let end = (region.base + region.length) as usize / FRAME_SIZE;
+
+            if (start..end).contains(&orders_start) {
+                allocator.free_range(start, orders_start);
+                allocator.free_range(orders_end, end);
+            } else {
+                allocator.free_range(start, end);
             }
         }
 
-        let bitmap_frame_start = (bitmap_address / 4096) as usize;
-        let bitmap_frame_count = bitmap_size.div_ceil(4096);
-        let bitmap_frame_end = bitmap_frame_start + bitmap_frame_count;
+        allocator.total_frames = allocator.available_frames();
+        log::info!(
+            "Usable memory: {} KiB, DMA32: {} KiB",
+            allocator.available_frames() * 4,
+            allocator.free_frames[Zone::Dma32 as usize] * 4
+        );
 
-        assert!(next_frame <= bitmap_frame_start);
-        if next_frame == bitmap_frame_start {
-            next_frame = bitmap_frame_end + 1;
-        }
-        usable_frames -= bitmap_frame_count;
-        (bitmap_frame_start..bitmap_frame_end).for_each(|index| bitmap.set(index, false));
+        allocator
+    }
 
-        log::info!("Usable memory: {} KiB", usable_frames * 4);
+    /// Frees the frames `start..end` as the largest aligned blocks that fit.
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        if start < DMA32_END && end > DMA32_END {
+            self.free_range(start, DMA32_END);
+            start = DMA32_END;
+        }
 
-        BitmapFrameAllocator {
-            bitmap,
-            usable_frames,
-            next_frame,
+        while start < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| start % (1 << order) == 0 && start + (1 << order) <= end)
+                .unwrap();
+            self.free_block(start, order);
+            start += 1 << order;
         }
     }
 
     pub fn available_frames(&self) -> usize {
-        self.usable_frames
+        self.free_frames.iter().sum()
+    }
+
+    pub fn available_frames_in(&self, zone: Zone) -> usize {
+        self.free_frames[zone as usize]
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn used_frames(&self, usage: FrameUsage) -> usize {
+        self.used_frames[usage as usize]
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let bytes = |frames: usize| (frames * FRAME_SIZE) as u64;
+        let used = |usage: FrameUsage| bytes(self.used_frames(usage));
+        MemoryStats {
+            total: bytes(self.total_frames),
+            free: bytes(self.available_frames()),
+            kernel_heap: used(FrameUsage::KernelHeap),
+            slab: used(FrameUsage::Slab),
+            kernel_stacks: used(FrameUsage::KernelStack),
+            modules: used(FrameUsage::Module),
+            page_tables: used(FrameUsage::PageTable),
+            user: used(FrameUsage::User),
+            dma: used(FrameUsage::Dma),
+            other: used(FrameUsage::Other),
+        }
+    }
+
+    pub fn allocate_for(&mut self, usage: FrameUsage) -> Option<PhysFrame> {
+        self.allocate_frames(0, Zone::Normal, usage)
     }
-}
 
-unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        if self.usable_frames == 0 {
-            log::error!("No more usable frames!");
+    /// Allocates `1 << order` contiguous frames aligned to their size.
+    pub fn allocate_frames(
+        &mut self,
+        order: usize,
+        zone: Zone,
+        usage: FrameUsage,
+    ) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
             return None;
         }
 
-        self.usable_frames -= 1;
-        self.bitmap.set(self.next_frame, false);
+        for &zone in zone.fallbacks() {
+            let Some(found) = (order..=MAX_ORDER)
+                .find(|&found| self.free_lists[zone as usize][found] != NO_FRAME)
+            else {
+                continue;
+            };
+
+            let index = self.free_lists[zone as usize][found];
+            self.remove_free(index, found);
+
+            // Give back the upper halves we do not need.
+            for split in (order..found).rev() {
+                self.push_free(index + (1 << split), split);
+            }
+
+            self.free_frames[zone as usize] -= 1 << order;
+            self.used_frames[usage as usize] += 1 << order;
+            self.orders[index] = ALLOCATED_BLOCK | usage as u8;
+            let address = PhysAddr::new((index * FRAME_SIZE) as u64);
+            return Some(PhysFrame::containing_address(address));
+        }
+
+        log::error!("No more usable frames of order {}!", order);
+        None
+    }
 
-        let address = self.next_frame * 4096;
+    /// Frees frames from `allocate_frames` with the same order.
+    pub unsafe fn deallocate_frames(&mut self, frame: PhysFrame, order: usize) {
+        if order == 0 && Self::release_shared(frame) {
+            return;
+        }
 
-        self.next_frame = (self.next_frame + 1..self.bitmap.len())
-            .find(|&index| self.bitmap.get(index))
-            .unwrap_or(self.bitmap.len());
+        let index = frame.start_address().as_u64() as usize / FRAME_SIZE;
+        self.take_usage(index, 1 << order);
+        self.free_block(index, order);
+    }
+
+    /// Stops counting `count` frames from the block at `index` as used.
+    /// Frames handed out before the allocator existed, such as the boot page
+    /// tables, were never counted.
+    fn take_usage(&mut self, index: usize, count: usize) {
+        let tag = core::mem::take(&mut self.orders[index]);
+        if tag & ALLOCATED_BLOCK != 0 {
+            self.used_frames[(tag & !ALLOCATED_BLOCK) as usize] -= count;
+        }
+    }
+
+    /// Allocates `count` contiguous frames whose start is aligned to
+    /// `1 << align_order` frames. Frames past `count` in the block are freed
+    /// right away.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align_order: usize,
+        zone: Zone,
+        usage: FrameUsage,
+    ) -> Option<PhysFrame> {
+        let order = order_of(count).max(align_order);
+        let frame = self.allocate_frames(order, zone, usage)?;
+
+        let start = frame.start_address().as_u64() as usize / FRAME_SIZE;
+        self.used_frames[usage as usize] -= (1 << order) - count;
+        self.free_range(start + count, start + (1 << order));
+        Some(frame)
+    }
+
+    /// Frees frames from `allocate_contiguous` with the same count.
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        let start = frame.start_address().as_u64() as usize / FRAME_SIZE;
+        self.take_usage(start, count);
+        self.free_range(start, start + count);
+    }
+
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        self.free_frames[Zone::of(index) as usize] += 1 << order;
+
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if self.orders.get(buddy) != Some(&(FREE_BLOCK | order as u8)) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+
+        self.push_free(index, order);
+    }
+
+    fn link(index: usize) -> &'static mut FreeLink {
+        let address = PhysAddr::new((index * FRAME_SIZE) as u64);
+        unsafe { &mut *convert_physical_to_virtual(address).as_mut_ptr::<FreeLink>() }
+    }
+
+    fn push_free(&mut self, index: usize, order: usize) {
+        let head = &mut self.free_lists[Zone::of(index) as usize][order];
+        *Self::link(index) = FreeLink {
+            prev: NO_FRAME,
+            next: *head,
+        };
+        if *head != NO_FRAME {
+            Self::link(*head).prev = index;
+        }
+        *head = index;
+        self.orders[index] = FREE_BLOCK | order as u8;
+    }
+
+    fn remove_free(&mut self, index: usize, order: usize) {
+        let FreeLink { prev, next } = *Self::link(index);
+        if prev != NO_FRAME {
+            Self::link(prev).next = next;
+        } else {
+            self.free_lists[Zone::of(index) as usize][order] = next;
+        }
+        if next != NO_FRAME {
+            Self::link(next).prev = prev;
+        }
+        self.orders[index] = 0;
+    }
+
+    /// Records another owner of an allocated frame. Every owner gives it
+    /// back with `deallocate_frame`, only the last one really frees it.
+    pub fn share_frame(frame: PhysFrame) {
+        Self::extra_owners(frame).fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn is_frame_shared(frame: PhysFrame) -> bool {
+        Self::extra_owners(frame).load(Ordering::Acquire) != 0
+    }
+
+    /// Drops one owner of a shared frame, returns false if it had only one.
+    fn release_shared(frame: PhysFrame) -> bool {
+        Self::extra_owners(frame)
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |owners| {
+                owners.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    fn extra_owners(frame: PhysFrame) -> &'static AtomicU32 {
+        let index = frame.start_address().as_u64() as usize / FRAME_SIZE;
+        &SHARED_FRAMES
+            .get()
+            .expect("Frame allocator not initialized")[index]
+    }
+}
 
-        Some(PhysFrame::containing_address(PhysAddr::new(address as u64)))
+/// Mappers allocate their page tables through this, other users say what
+/// their frames are for with `allocate_for` or `allocate_frames`.
+unsafe impl<S: PageSize> FrameAllocator<S> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let order = order_of_size::<S>();
+        let frame = self.allocate_frames(order, Zone::Normal, FrameUsage::PageTable)?;
+        PhysFrame::from_start_address(frame.start_address()).ok()
     }
 }
 
-impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
-    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        let index = frame.start_address().as_u64() / 4096;
-        self.bitmap.set(index as usize, true);
-        self.usable_frames += 1;
+impl<S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_frames(frame, order_of_size::<S>());
     }
 }
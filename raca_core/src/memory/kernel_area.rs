@@ -0,0 +1,217 @@
+use alloc::collections::btree_map::BTreeMap;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameDeallocator, Mapper};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{FrameUsage, TlbBatch, FRAME_ALLOCATOR, KERNEL_PAGE_TABLE, PAGE_SIZE};
+use super::{KERNEL_AREA_START, KERNEL_STACK_START};
+use crate::error::{RcError, RcResult};
+
+const REGION_SIZE: u64 = 512 << 30;
+
+/// Left unmapped after every area so that running off its end faults. The
+/// page below an area is always another area's guard or unused, so running
+/// off its start faults too.
+pub const GUARD_SIZE: u64 = PAGE_SIZE;
+
+pub static KERNEL_AREAS: VirtualRegion = VirtualRegion::new(KERNEL_AREA_START, REGION_SIZE);
+
+/// Kernel stacks of threads, kept apart so that faults in their guard pages
+/// are easy to recognize.
+pub static KERNEL_STACKS: VirtualRegion = VirtualRegion::new(KERNEL_STACK_START, REGION_SIZE);
+
+/// A range of kernel virtual addresses that areas are reserved from.
+pub struct VirtualRegion {
+    start: u64,
+    size: u64,
+    /// Free ranges by start address. Empty until the first reservation.
+    free_ranges: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl VirtualRegion {
+    const fn new(start: u64, size: u64) -> Self {
+        Self {
+            start,
+            size,
+            free_ranges: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn start_address(&self) -> VirtAddr {
+        VirtAddr::new(self.start)
+    }
+
+    pub fn end_address(&self) -> VirtAddr {
+        VirtAddr::new(self.start + self.size)
+    }
+
+    fn reserve(&self, size: u64) -> RcResult<VirtAddr> {
+        let mut free_ranges = self.free_ranges.lock();
+        if free_ranges.is_empty() {
+            free_ranges.insert(self.start, self.size);
+        }
+
+        let (&start, &free_size) = free_ranges
+            .iter()
+            .find(|(_, &free_size)| free_size >= size)
+            .ok_or(RcError::NO_MEMORY)?;
+
+        free_ranges.remove(&start);
+        if free_size > size {
+            free_ranges.insert(start + size, free_size - size);
+        }
+        Ok(VirtAddr::new(start))
+    }
+
+    fn release(&self, start: VirtAddr, size: u64) {
+        let mut free_ranges = self.free_ranges.lock();
+        let start = start.as_u64();
+        let mut size = size;
+
+        if let Some((&next_start, &next_size)) = free_ranges.range(start..).next() {
+            if start + size == next_start {
+                free_ranges.remove(&next_start);
+                size += next_size;
+            }
+        }
+        if let Some((&prev_start, prev_size)) = free_ranges.range_mut(..start).next_back() {
+            if prev_start + *prev_size == start {
+                *prev_size += size;
+                return;
+            }
+        }
+        free_ranges.insert(start, size);
+    }
+}
+
+/// A range of kernel virtual memory, mapped in every address space and
+/// unmapped again when dropped. Used for mappings too large or too long
+/// lived for the heap.
+pub struct KernelArea {
+    region: &'static VirtualRegion,
+    start: VirtAddr,
+    size: u64,
+    /// Whether the frames were allocated for this area and are freed with it.
+    owns_frames: bool,
+}
+
+impl KernelArea {
+    /// Maps `size` bytes of fresh frames, counted as `usage`.
+    pub fn new(size: usize, flags: PageTableFlags, usage: FrameUsage) -> RcResult<Self> {
+        Self::new_in(&KERNEL_AREAS, size, flags, usage)
+    }
+
+    pub fn new_in(
+        region: &'static VirtualRegion,
+        size: usize,
+        flags: PageTableFlags,
+        usage: FrameUsage,
+    ) -> RcResult<Self> {
+        let area = Self::reserve(region, size, true)?;
+        interrupts::without_interrupts(|| {
+            let mut page_table = KERNEL_PAGE_TABLE.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            for page in area.pages() {
+                let frame = frame_allocator
+                    .allocate_for(usage)
+                    .ok_or(RcError::NO_MEMORY)?;
+                let result =
+                    unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) };
+                match result {
+                    Ok(flush) => flush.flush(),
+                    Err(_) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        return Err(RcError::NO_MEMORY);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(area)
+    }
+
+    /// Maps `size` bytes of physical memory from `physical_address`, e.g. MMIO
+    /// registers. The frames are left alone when the area is dropped.
+    pub fn map_physical(
+        physical_address: PhysAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> RcResult<Self> {
+        if !physical_address.is_aligned(PAGE_SIZE) {
+            return Err(RcError::INVALID_ARGS);
+        }
+
+        let area = Self::reserve(&KERNEL_AREAS, size, false)?;
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(physical_address);
+        interrupts::without_interrupts(|| {
+            let mut page_table = KERNEL_PAGE_TABLE.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            for (index, page) in area.pages().enumerate() {
+                let frame = start_frame + index as u64;
+                unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) }
+                    .map_err(|_| RcError::NO_MEMORY)?
+                    .flush();
+            }
+            Ok::<_, RcError>(())
+        })?;
+        Ok(area)
+    }
+
+    fn reserve(region: &'static VirtualRegion, size: usize, owns_frames: bool) -> RcResult<Self> {
+        if size == 0 {
+            return Err(RcError::INVALID_ARGS);
+        }
+        let size = (size as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let start = interrupts::without_interrupts(|| region.reserve(size + GUARD_SIZE))?;
+        Ok(Self {
+            region,
+            start,
+            size,
+            owns_frames,
+        })
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start_page = Page::containing_address(self.start);
+        (0..self.size / PAGE_SIZE).map(move |index| start_page + index)
+    }
+
+    pub fn start_address(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end_address(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl Drop for KernelArea {
+    /// Waits for every CPU to forget the mappings, so the area must not be
+    /// dropped while holding locks other CPUs may spin on.
+    fn drop(&mut self) {
+        let mut batch = TlbBatch::kernel();
+        interrupts::without_interrupts(|| {
+            let mut page_table = KERNEL_PAGE_TABLE.lock();
+            for page in self.pages() {
+                let Ok((frame, flush)) = page_table.unmap(page) else {
+                    continue;
+                };
+                flush.ignore();
+                batch.add(&page_table, page);
+                if self.owns_frames {
+                    batch.free_after_flush(frame);
+                }
+            }
+        });
+        batch.flush();
+
+        let reserved_size = self.size + GUARD_SIZE;
+        interrupts::without_interrupts(|| self.region.release(self.start, reserved_size));
+    }
+}
@@ -1,36 +1,74 @@
 use alloc::alloc::Layout;
-use talc::{ClaimOnOom, Span, Talc, Talck};
+use talc::{OomHandler, Span, Talc, Talck};
+use x86_64::structures::paging::Size4KiB;
 use x86_64::VirtAddr;
 
-use super::MappingType;
-use super::KERNEL_PAGE_TABLE;
+use super::{ref_current_page_table, FrameUsage, MappingType, HEAP_START, PAGE_SIZE};
 use crate::memory::MemoryManager;
 
-pub const HEAP_START: usize = 0x114514000000;
-pub const HEAP_SIZE: usize = 128 * 1024 * 1024;
+pub const HEAP_INITIAL_SIZE: usize = 4 * 1024 * 1024;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024 * 1024;
+
+/// The heap grows by at least this much at a time.
+const HEAP_GROW_SIZE: usize = 1024 * 1024;
+
+/// Maps more memory at the end of the heap when it runs out.
+struct GrowOnOom {
+    heap: Span,
+}
+
+impl OomHandler for GrowOnOom {
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        let heap = talc.oom_handler.heap;
+        let (base, acme) = heap.get_base_acme().ok_or(())?;
+
+        let required = (layout.size() + layout.align()).max(HEAP_GROW_SIZE);
+        let grow_size = required.next_multiple_of(PAGE_SIZE as usize);
+        if acme as usize + grow_size > HEAP_START as usize + HEAP_MAX_SIZE {
+            return Err(());
+        }
+
+        map_heap(VirtAddr::from_ptr(acme), grow_size)?;
+        let new_heap = Span::new(base, acme.wrapping_add(grow_size));
+        talc.oom_handler.heap = unsafe { talc.extend(heap, new_heap) };
+        Ok(())
+    }
+}
 
 #[global_allocator]
-static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> =
-    Talc::new(unsafe { ClaimOnOom::new(Span::empty()) }).lock();
+static ALLOCATOR: Talck<spin::Mutex<()>, GrowOnOom> = Talc::new(GrowOnOom {
+    heap: Span::empty(),
+})
+.lock();
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("Kernel heap allocation error: {:?}", layout)
 }
 
-pub fn init_heap() {
-    let heap_start = VirtAddr::new(HEAP_START as u64);
-
-    MemoryManager::alloc_range(
-        heap_start,
-        HEAP_SIZE as u64,
+/// Maps heap memory through the current page table rather than
+/// `KERNEL_PAGE_TABLE`, whose holder may be the one allocating. The heap has
+/// PML4 entries of its own, shared by every page table, so only the heap's
+/// tables are written.
+fn map_heap(start: VirtAddr, size: usize) -> Result<(), ()> {
+    let mut page_table = unsafe { ref_current_page_table() };
+    MemoryManager::<Size4KiB>::alloc_range(
+        start,
+        size as u64,
         MappingType::KernelData.flags(),
-        &mut KERNEL_PAGE_TABLE.lock(),
+        FrameUsage::KernelHeap,
+        &mut page_table,
     )
-    .unwrap();
+    .map_err(|_| ())
+}
+
+pub fn init_heap() {
+    let heap_start = VirtAddr::new(HEAP_START);
+    map_heap(heap_start, HEAP_INITIAL_SIZE).expect("Failed to map the kernel heap");
 
     unsafe {
-        let arena = Span::from_base_size(heap_start.as_mut_ptr(), HEAP_SIZE);
-        ALLOCATOR.lock().claim(arena).unwrap();
+        let arena = Span::from_base_size(heap_start.as_mut_ptr(), HEAP_INITIAL_SIZE);
+        let mut talc = ALLOCATOR.lock();
+        talc.oom_handler.heap = talc.claim(arena).unwrap();
     }
 }
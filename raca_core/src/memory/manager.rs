@@ -6,7 +6,7 @@ use x86_64::structures::paging::{Mapper, OffsetPageTable, PageTableFlags};
 use x86_64::structures::paging::{Page, PageSize, Size4KiB};
 use x86_64::VirtAddr;
 
-use super::BitmapFrameAllocator;
+use super::{order_of, BuddyFrameAllocator, FrameUsage, Zone};
 
 pub enum MappingType {
     UserCode,
@@ -44,11 +44,12 @@ impl<S: PageSize> MemoryManager<S> {
         start_address: VirtAddr,
         length: u64,
         flags: PageTableFlags,
+        usage: FrameUsage,
         page_table: &mut OffsetPageTable<'static>,
     ) -> Result<(), MapToError<S>>
     where
         OffsetPageTable<'static>: Mapper<S>,
-        BitmapFrameAllocator: FrameAllocator<S>,
+        BuddyFrameAllocator: FrameAllocator<S>,
     {
         interrupts::without_interrupts(|| {
             let page_range = {
@@ -56,11 +57,13 @@ impl<S: PageSize> MemoryManager<S> {
                 let end_page = Page::containing_address(start_address + length - 1u64);
                 Page::range_inclusive(start_page, end_page)
             };
+            let order = order_of((S::SIZE / Size4KiB::SIZE) as usize);
             let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
 
             for page in page_range {
                 let frame = frame_allocator
-                    .allocate_frame()
+                    .allocate_frames(order, Zone::Normal, usage)
+                    .and_then(|frame| PhysFrame::from_start_address(frame.start_address()).ok())
                     .ok_or(MapToError::FrameAllocationFailed)?;
                 unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) }
                     .map(|flush| flush.flush())?;
@@ -79,7 +82,7 @@ impl<S: PageSize> MemoryManager<S> {
     ) -> Result<(), MapToError<S>>
     where
         OffsetPageTable<'static>: Mapper<S>,
-        BitmapFrameAllocator: FrameAllocator<S>,
+        BuddyFrameAllocator: FrameAllocator<S>,
     {
         interrupts::without_interrupts(|| {
             let page_range = {
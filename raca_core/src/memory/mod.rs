@@ -1,20 +1,34 @@
 use limine::request::{HhdmRequest, MemoryMapRequest};
 use spin::{Lazy, Mutex};
+use x86_64::instructions::interrupts;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::{OffsetPageTable, PageTable};
 use x86_64::{PhysAddr, VirtAddr};
 
 mod dma;
 mod frame;
+mod kernel_area;
 mod kernel_heap;
 mod manager;
 mod page_table;
+mod slab;
+mod tlb;
+mod vma;
 
-pub use dma::DmaMemoryManager;
-pub use frame::BitmapFrameAllocator;
-pub use kernel_heap::init_heap;
+pub use dma::{DmaBuffer, DmaCache, DmaConstraints};
+pub use frame::{order_of, BuddyFrameAllocator, FrameUsage, MemoryStats, Zone, MAX_ORDER};
+pub use kernel_area::{KernelArea, VirtualRegion, GUARD_SIZE, KERNEL_AREAS, KERNEL_STACKS};
 pub use manager::{MappingType, MemoryManager};
 pub use page_table::*;
+pub use slab::{SlabAllocator, SlabBox, SlabStats};
+pub use tlb::{forget_address_space, handle_shootdown, init_pcid, join_shootdowns};
+pub use tlb::{PcidCache, TlbBatch};
+pub use vma::*;
+
+/// Kernel memory mapped at run time, each area in PML4 entries of its own.
+pub const HEAP_START: u64 = 0xffff_c080_0000_0000;
+pub const KERNEL_AREA_START: u64 = 0xffff_c100_0000_0000;
+pub const KERNEL_STACK_START: u64 = 0xffff_c180_0000_0000;
 
 #[used]
 #[link_section = ".requests"]
@@ -27,9 +41,9 @@ static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 pub static PHYSICAL_MEMORY_OFFSET: Lazy<u64> =
     Lazy::new(|| HHDM_REQUEST.get_response().unwrap().offset());
 
-pub static FRAME_ALLOCATOR: Lazy<Mutex<BitmapFrameAllocator>> = Lazy::new(|| {
+pub static FRAME_ALLOCATOR: Lazy<Mutex<BuddyFrameAllocator>> = Lazy::new(|| {
     let memory_map = MEMORY_MAP_REQUEST.get_response().unwrap();
-    Mutex::new(BitmapFrameAllocator::init(memory_map))
+    Mutex::new(BuddyFrameAllocator::init(memory_map))
 });
 
 pub static KERNEL_PAGE_TABLE: Lazy<Mutex<OffsetPageTable>> = Lazy::new(|| {
@@ -37,6 +51,48 @@ pub static KERNEL_PAGE_TABLE: Lazy<Mutex<OffsetPageTable>> = Lazy::new(|| {
     Mutex::new(page_table)
 });
 
+pub fn init() {
+    init_kernel_half(&mut KERNEL_PAGE_TABLE.lock());
+    kernel_heap::init_heap();
+}
+
+pub fn memory_stats() -> MemoryStats {
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().stats())
+}
+
+/// Dumps physical memory usage and the slab caches to the kernel log.
+pub fn log_memory_stats() {
+    let stats = memory_stats();
+    let rows = [
+        ("Total", stats.total),
+        ("Free", stats.free),
+        ("Kernel heap", stats.kernel_heap),
+        ("Slab", stats.slab),
+        ("Kernel stacks", stats.kernel_stacks),
+        ("Modules", stats.modules),
+        ("Page tables", stats.page_tables),
+        ("User", stats.user),
+        ("DMA", stats.dma),
+        ("Other", stats.other),
+    ];
+    log::info!("Physical memory:");
+    for (name, bytes) in rows {
+        log::info!("  {:<14}{:>12} KiB", name, bytes / 1024);
+    }
+
+    log::info!("Slab caches (size, slabs, in use, cached, free):");
+    for cache in SlabAllocator::stats() {
+        log::info!(
+            "  {:>5}{:>8}{:>8}{:>8}{:>8}",
+            cache.object_size,
+            cache.slabs,
+            cache.in_use,
+            cache.cached,
+            cache.free
+        );
+    }
+}
+
 #[inline]
 pub fn convert_physical_to_virtual(physical_address: PhysAddr) -> VirtAddr {
     VirtAddr::new(physical_address.as_u64() + PHYSICAL_MEMORY_OFFSET.clone())
@@ -1,19 +1,61 @@
 use alloc::format;
+use x86_64::instructions::interrupts;
 use x86_64::structures::paging::mapper::*;
 use x86_64::structures::paging::FrameAllocator;
-use x86_64::structures::paging::FrameDeallocator;
-use x86_64::structures::paging::PhysFrame;
 use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 
-use super::MappingType;
 use super::FRAME_ALLOCATOR;
-use super::{convert_physical_to_virtual, BitmapFrameAllocator, PHYSICAL_MEMORY_OFFSET};
+use super::{convert_physical_to_virtual, forget_address_space};
+use super::{BuddyFrameAllocator, PHYSICAL_MEMORY_OFFSET};
+
+/// Marks user leaf entries whose frame belongs to someone else, e.g. a VMO,
+/// so that tearing down the page table leaves it alone.
+pub const BORROWED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Marks user leaf entries that were writable before being shared by a fork.
+/// Writing to them copies the frame first.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Flags of the intermediate tables for user mappings. Leaf entries decide
+/// what is really allowed, so permissions can change without touching these.
+pub const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// PML4 entries below this map user space, the rest map the kernel.
+const USER_PML4_ENTRIES: usize = 256;
+
+/// Fills every empty kernel-half PML4 entry of `page_table` with an empty
+/// table. Address spaces link to these tables instead of copying them, so
+/// kernel mappings made at any time show up in all of them.
+pub fn init_kernel_half(page_table: &mut OffsetPageTable<'static>) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+    for entry in page_table
+        .level_4_table_mut()
+        .iter_mut()
+        .skip(USER_PML4_ENTRIES)
+    {
+        if !entry.is_unused() {
+            continue;
+        }
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut *frame_allocator)
+            .expect("Failed to allocate frame for page table");
+        unsafe {
+            let table = convert_physical_to_virtual(frame.start_address());
+            table.as_mut_ptr::<PageTable>().write(PageTable::new());
+        }
+        entry.set_frame(frame, flags);
+    }
+}
 
 pub trait ExtendedPageTable {
     fn physical_address(&self) -> PhysAddr;
     fn write_to_mapped_address(&self, buffer: &[u8], address: VirtAddr);
-    unsafe fn deep_copy(&self) -> OffsetPageTable<'static>;
+    unsafe fn new_address_space(&self) -> OffsetPageTable<'static>;
     unsafe fn free_user_page_table(&self);
 }
 
@@ -34,82 +76,64 @@ impl ExtendedPageTable for OffsetPageTable<'_> {
         }
     }
 
-    unsafe fn deep_copy(&self) -> OffsetPageTable<'static> {
+    /// Makes a page table with an empty user half that shares the kernel
+    /// half of this one.
+    unsafe fn new_address_space(&self) -> OffsetPageTable<'static> {
         let virtual_address = convert_physical_to_virtual(self.physical_address());
         let source_table = &*virtual_address.as_ptr::<PageTable>();
 
-        let mut frame_allocator = FRAME_ALLOCATOR.lock();
-        let mut new_page_table = new_from_allocate(&mut frame_allocator);
-        let target_table = new_page_table.level_4_table_mut();
+        let frame = interrupts::without_interrupts(|| {
+            FrameAllocator::<Size4KiB>::allocate_frame(&mut *FRAME_ALLOCATOR.lock())
+        })
+        .expect("Failed to allocate frame for page table");
+        let target_table =
+            &mut *convert_physical_to_virtual(frame.start_address()).as_mut_ptr::<PageTable>();
+
+        *target_table = PageTable::new();
+        let kernel_half = source_table.iter().skip(USER_PML4_ENTRIES);
+        for (target, source) in target_table
+            .iter_mut()
+            .skip(USER_PML4_ENTRIES)
+            .zip(kernel_half)
+        {
+            *target = source.clone();
+        }
 
-        new_from_recursion(&mut frame_allocator, source_table, target_table, 4);
-        new_page_table
+        let physical_memory_offset = VirtAddr::new(*PHYSICAL_MEMORY_OFFSET);
+        OffsetPageTable::new(target_table, physical_memory_offset)
     }
 
+    /// Frees the page table and every user frame it maps. Must not be the
+    /// current page table.
     unsafe fn free_user_page_table(&self) {
-        let mut frame_allocator = FRAME_ALLOCATOR.lock();
-        free_pages_recursion(&mut frame_allocator, self.physical_address(), 4);
-    }
-}
-
-unsafe fn new_from_allocate(
-    frame_allocator: &mut BitmapFrameAllocator,
-) -> OffsetPageTable<'static> {
-    let page_table_address = BitmapFrameAllocator::allocate_frame(frame_allocator)
-        .expect("Failed to allocate frame for page table")
-        .start_address();
-
-    let new_page_table =
-        &mut *convert_physical_to_virtual(page_table_address).as_mut_ptr::<PageTable>();
-
-    let physical_memory_offset = VirtAddr::new(*PHYSICAL_MEMORY_OFFSET);
-    let page_table = OffsetPageTable::new(new_page_table, physical_memory_offset);
-
-    page_table
-}
-
-unsafe fn new_from_recursion(
-    frame_allocator: &mut BitmapFrameAllocator,
-    source_page_table: &PageTable,
-    target_page_table: &mut PageTable,
-    page_table_level: u8,
-) {
-    for (index, entry) in source_page_table.iter().enumerate() {
-        if (page_table_level == 1)
-            || entry.is_unused()
-            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
-        {
-            target_page_table[index].set_addr(entry.addr(), entry.flags());
-            continue;
-        }
-        let mut new_page_table = new_from_allocate(frame_allocator);
-        let new_page_table_address = PhysAddr::new(new_page_table.physical_address().as_u64());
-        target_page_table[index].set_addr(new_page_table_address, entry.flags());
-
-        let source_page_table_next = &*convert_physical_to_virtual(entry.addr()).as_ptr();
-        let target_page_table_next = new_page_table.level_4_table_mut();
-
-        new_from_recursion(
-            frame_allocator,
-            source_page_table_next,
-            target_page_table_next,
-            page_table_level - 1,
-        );
+        let physical_address = self.physical_address();
+        let level_4_table =
+            &mut *convert_physical_to_virtual(physical_address).as_mut_ptr::<PageTable>();
+        forget_address_space(physical_address);
+
+        interrupts::without_interrupts(|| {
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            for entry in level_4_table.iter_mut().take(USER_PML4_ENTRIES) {
+                if !entry.is_unused() {
+                    free_user_tables(&mut frame_allocator, entry.addr(), 3);
+                    entry.set_unused();
+                }
+            }
+            frame_allocator.deallocate_frames(PhysFrame::containing_address(physical_address), 0);
+        });
     }
 }
 
-unsafe fn free_pages_recursion(
-    frame_allocator: &mut BitmapFrameAllocator,
+/// Frees a user page table at `page_table_level` with everything it maps,
+/// except frames borrowed from someone else. Shared frames only lose an
+/// owner.
+unsafe fn free_user_tables(
+    frame_allocator: &mut BuddyFrameAllocator,
     physical_address: PhysAddr,
     page_table_level: u8,
 ) {
-    if page_table_level == 0 {
-        frame_allocator.deallocate_frame(PhysFrame::containing_address(physical_address));
-        return;
-    }
-
     let virtual_address = convert_physical_to_virtual(physical_address);
-    let page_table = &mut *(virtual_address.as_mut_ptr::<PageTable>());
+    let page_table = &*(virtual_address.as_ptr::<PageTable>());
 
     for entry in page_table.iter() {
         if entry.is_unused() {
@@ -117,15 +141,15 @@ unsafe fn free_pages_recursion(
         }
 
         if page_table_level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-            if entry.flags().contains(MappingType::UserCode.flags()) {
-                if let Ok(frame) = entry.frame() {
-                    frame_allocator.deallocate_frame(frame);
-                }
+            if !entry.flags().contains(BORROWED_FRAME) {
+                let frame = PhysFrame::containing_address(entry.addr());
+                let order = 9 * (page_table_level as usize - 1);
+                frame_allocator.deallocate_frames(frame, order);
             }
         } else {
-            free_pages_recursion(frame_allocator, entry.addr(), page_table_level - 1);
+            free_user_tables(frame_allocator, entry.addr(), page_table_level - 1);
         }
     }
 
-    frame_allocator.deallocate_frame(PhysFrame::containing_address(physical_address));
+    frame_allocator.deallocate_frames(PhysFrame::containing_address(physical_address), 0);
 }
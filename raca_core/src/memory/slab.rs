@@ -0,0 +1,249 @@
+use alloc::alloc::Global;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::{AllocError, Allocator, Layout};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+use super::{convert_physical_to_virtual, FrameUsage, Zone, FRAME_ALLOCATOR};
+use crate::arch::smp::current_cpu_index;
+
+const SIZE_CLASSES: [usize; 7] = [32, 64, 128, 256, 512, 1024, 2048];
+
+/// Each slab is `1 << SLAB_ORDER` frames, carved into objects of one size.
+const SLAB_ORDER: usize = 2;
+const SLAB_SIZE: usize = (Size4KiB::SIZE as usize) << SLAB_ORDER;
+
+const MAGAZINE_SIZE: usize = 32;
+
+/// CPUs beyond this go straight to the depot, which is slower but still
+/// correct.
+const MAX_CPUS: usize = 32;
+
+/// A `Box` whose memory comes from the slab caches.
+pub type SlabBox<T> = Box<T, SlabAllocator>;
+
+/// Objects a CPU allocates from and frees to without touching the depot.
+struct Magazine {
+    objects: [usize; MAGAZINE_SIZE],
+    count: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [0; MAGAZINE_SIZE],
+            count: 0,
+        }
+    }
+}
+
+/// Free objects shared by all CPUs, linked through their first word.
+struct Depot {
+    free_list: usize,
+    free_count: usize,
+    slab_count: usize,
+}
+
+impl Depot {
+    fn pop(&mut self) -> Option<usize> {
+        if self.free_list == 0 {
+            return None;
+        }
+        let object = self.free_list;
+        self.free_list = unsafe { (object as *const usize).read() };
+        self.free_count -= 1;
+        Some(object)
+    }
+
+    fn push(&mut self, object: usize) {
+        unsafe { (object as *mut usize).write(self.free_list) };
+        self.free_list = object;
+        self.free_count += 1;
+    }
+
+    fn grow(&mut self, object_size: usize) -> Option<()> {
+        let frame = interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR
+                .lock()
+                .allocate_frames(SLAB_ORDER, Zone::Normal, FrameUsage::Slab)
+        })?;
+        let start = convert_physical_to_virtual(frame.start_address()).as_u64() as usize;
+
+        for object in (start..start + SLAB_SIZE).step_by(object_size).rev() {
+            self.push(object);
+        }
+        self.slab_count += 1;
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub object_size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    /// Free objects held in per-CPU magazines.
+    pub cached: usize,
+    /// Free objects in the shared depot.
+    pub free: usize,
+}
+
+/// Objects of one size. Slabs are never given back to the frame allocator.
+struct SlabCache {
+    object_size: usize,
+    magazines: [Mutex<Magazine>; MAX_CPUS],
+    depot: Mutex<Depot>,
+    in_use: AtomicUsize,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            magazines: [const { Mutex::new(Magazine::new()) }; MAX_CPUS],
+            depot: Mutex::new(Depot {
+                free_list: 0,
+                free_count: 0,
+                slab_count: 0,
+            }),
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    /// The magazine of the running CPU. CPUs that have not loaded their
+    /// `CpuInfo` yet use the one of the bootstrap processor.
+    fn magazine(&self) -> Option<&Mutex<Magazine>> {
+        self.magazines.get(current_cpu_index().unwrap_or(0))
+    }
+
+    fn allocate(&self) -> Option<usize> {
+        let object = interrupts::without_interrupts(|| {
+            let Some(magazine) = self.magazine() else {
+                let mut depot = self.depot.lock();
+                if depot.free_count == 0 {
+                    depot.grow(self.object_size)?;
+                }
+                return depot.pop();
+            };
+
+            let mut magazine = magazine.lock();
+            if magazine.count == 0 {
+                let mut depot = self.depot.lock();
+                if depot.free_count == 0 {
+                    depot.grow(self.object_size)?;
+                }
+                while magazine.count < MAGAZINE_SIZE / 2 {
+                    let Some(object) = depot.pop() else {
+                        break;
+                    };
+                    let count = magazine.count;
+                    magazine.objects[count] = object;
+                    magazine.count += 1;
+                }
+            }
+
+            magazine.count -= 1;
+            Some(magazine.objects[magazine.count])
+        })?;
+
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        Some(object)
+    }
+
+    fn deallocate(&self, object: usize) {
+        interrupts::without_interrupts(|| {
+            let Some(magazine) = self.magazine() else {
+                self.depot.lock().push(object);
+                return;
+            };
+
+            let mut magazine = magazine.lock();
+            if magazine.count == MAGAZINE_SIZE {
+                let mut depot = self.depot.lock();
+                while magazine.count > MAGAZINE_SIZE / 2 {
+                    magazine.count -= 1;
+                    depot.push(magazine.objects[magazine.count]);
+                }
+            }
+
+            let count = magazine.count;
+            magazine.objects[count] = object;
+            magazine.count += 1;
+        });
+
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> SlabStats {
+        let cached = interrupts::without_interrupts(|| {
+            self.magazines
+                .iter()
+                .map(|magazine| magazine.lock().count)
+                .sum()
+        });
+        let depot = interrupts::without_interrupts(|| {
+            let depot = self.depot.lock();
+            (depot.slab_count, depot.free_count)
+        });
+
+        SlabStats {
+            object_size: self.object_size,
+            slabs: depot.0,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            cached,
+            free: depot.1,
+        }
+    }
+}
+
+static SLAB_CACHES: [SlabCache; SIZE_CLASSES.len()] = [
+    SlabCache::new(SIZE_CLASSES[0]),
+    SlabCache::new(SIZE_CLASSES[1]),
+    SlabCache::new(SIZE_CLASSES[2]),
+    SlabCache::new(SIZE_CLASSES[3]),
+    SlabCache::new(SIZE_CLASSES[4]),
+    SlabCache::new(SIZE_CLASSES[5]),
+    SlabCache::new(SIZE_CLASSES[6]),
+];
+
+/// Allocates small objects from per-CPU slab caches, and anything larger
+/// from the kernel heap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabAllocator;
+
+impl SlabAllocator {
+    fn cache(layout: Layout) -> Option<&'static SlabCache> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES
+            .iter()
+            .position(|&class| class >= size)
+            .map(|index| &SLAB_CACHES[index])
+    }
+
+    pub fn stats() -> Vec<SlabStats> {
+        SLAB_CACHES.iter().map(SlabCache::stats).collect()
+    }
+}
+
+unsafe impl Allocator for SlabAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let Some(cache) = Self::cache(layout) else {
+            return Global.allocate(layout);
+        };
+
+        let object = cache.allocate().ok_or(AllocError)?;
+        let object = NonNull::new(object as *mut u8).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(object, cache.object_size))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match Self::cache(layout) {
+            Some(cache) => cache.deallocate(ptr.as_ptr() as usize),
+            None => Global.deallocate(ptr, layout),
+        }
+    }
+}
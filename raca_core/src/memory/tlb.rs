@@ -0,0 +1,331 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::{FrameDeallocator, OffsetPageTable, Page, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{ExtendedPageTable, FRAME_ALLOCATOR};
+use crate::arch::apic;
+use crate::arch::interrupts::InterruptIndex;
+use crate::arch::smp::{current_cpu, current_cpu_id, CPUS};
+
+/// Batches with more pages flush the whole TLB instead.
+const MAX_BATCH_PAGES: usize = 32;
+
+/// Address spaces each CPU keeps TLB entries around for. PCID 0 is left to
+/// the page table the CPU booted with and to threads on their way out.
+const PCID_COUNT: usize = 32;
+
+/// Keeps the TLB entries tagged with the new PCID when written to CR3.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// Whether CR3 carries a PCID. CPUs without them flush the TLB whenever CR3
+/// changes.
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Tags TLB entries with their address space on this CPU, if it can.
+pub fn init_pcid() {
+    if unsafe { __cpuid(1) }.ecx & (1 << 17) == 0 {
+        return;
+    }
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// The address spaces a CPU has TLB entries for, one per PCID.
+pub struct PcidCache {
+    page_tables: [AtomicU64; PCID_COUNT],
+    /// Set when mappings changed while the CPU ran another address space,
+    /// so that the entries go before it switches back.
+    stale: [AtomicBool; PCID_COUNT],
+    next_victim: AtomicUsize,
+}
+
+impl PcidCache {
+    pub fn new() -> Self {
+        Self {
+            page_tables: [const { AtomicU64::new(0) }; PCID_COUNT],
+            stale: [const { AtomicBool::new(false) }; PCID_COUNT],
+            next_victim: AtomicUsize::new(0),
+        }
+    }
+
+    /// The value to load into CR3 to switch to `page_table`. It must already
+    /// be the active page table of the CPU: a shootdown that marks the
+    /// entries stale too late to be seen here then finds the CPU in that
+    /// address space and interrupts it.
+    pub fn cr3_for(&self, page_table: PhysAddr) -> u64 {
+        let address = page_table.as_u64();
+        if !PCID_ENABLED.load(Ordering::Relaxed) {
+            return address;
+        }
+
+        let position = self
+            .page_tables
+            .iter()
+            .position(|slot| slot.load(Ordering::SeqCst) == address);
+        let (index, keep_entries) = match position {
+            Some(index) => (index, !self.stale[index].swap(false, Ordering::SeqCst)),
+            None => {
+                let index = self.next_victim.fetch_add(1, Ordering::Relaxed) % PCID_COUNT;
+                self.page_tables[index].store(address, Ordering::SeqCst);
+                self.stale[index].store(false, Ordering::SeqCst);
+                (index, false)
+            }
+        };
+
+        let pcid = index as u64 + 1;
+        match keep_entries {
+            true => address | pcid | CR3_NO_FLUSH,
+            false => address | pcid,
+        }
+    }
+
+    fn forget(&self, page_table: PhysAddr) {
+        let address = page_table.as_u64();
+        for (slot, stale) in self.page_tables.iter().zip(self.stale.iter()) {
+            if slot.load(Ordering::SeqCst) == address {
+                stale.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Makes every CPU drop what it cached for `page_table` before using it
+/// again, e.g. because it is about to be freed and its frame may become
+/// another page table.
+pub fn forget_address_space(page_table: PhysAddr) {
+    let cpus = CPUS.read();
+    for &cpu_id in cpus.iter_id() {
+        cpus.get(cpu_id).pcids().forget(page_table);
+    }
+}
+
+/// What the CPUs interrupted by a shootdown flush. Only the holder of
+/// `SHOOTDOWN_LOCK` writes it.
+struct Shootdown {
+    kernel: AtomicBool,
+    pages: [AtomicU64; MAX_BATCH_PAGES],
+    /// More than `MAX_BATCH_PAGES` means everything.
+    page_count: AtomicUsize,
+    /// CPUs that have not flushed yet.
+    remaining: AtomicUsize,
+}
+
+static SHOOTDOWN: Shootdown = Shootdown {
+    kernel: AtomicBool::new(false),
+    pages: [const { AtomicU64::new(0) }; MAX_BATCH_PAGES],
+    page_count: AtomicUsize::new(0),
+    remaining: AtomicUsize::new(0),
+};
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+fn flush_pages(pages: impl Iterator<Item = VirtAddr>, page_count: usize, kernel: bool) {
+    if kernel && PCID_ENABLED.load(Ordering::Relaxed) {
+        // Kernel entries are cached under every PCID, but `invlpg` and CR3
+        // reloads only reach the current one.
+        flush_all_address_spaces();
+    } else if page_count > MAX_BATCH_PAGES {
+        flush_current_address_space();
+    } else {
+        pages.for_each(tlb::flush);
+    }
+}
+
+/// Reloads CR3 as it is, which keeps the PCID, unlike `tlb::flush_all`.
+fn flush_current_address_space() {
+    unsafe { asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _) };
+}
+
+/// Toggling global pages flushes the entries of all PCIDs.
+fn flush_all_address_spaces() {
+    let flags = Cr4::read();
+    unsafe {
+        Cr4::write(flags ^ Cr4Flags::PAGE_GLOBAL);
+        Cr4::write(flags);
+    }
+}
+
+/// Mappings changed in one address space, or in the kernel half shared by
+/// all of them, that have to leave the TLB of every CPU that may cache
+/// them. Frames that were unmapped are freed only afterwards, once no CPU
+/// can reach them anymore.
+///
+/// Flushing waits for the other CPUs, so it must not happen while holding a
+/// lock they may spin on with interrupts disabled. The batch is flushed
+/// when dropped.
+#[must_use = "mappings stay cached until the batch is flushed"]
+pub struct TlbBatch {
+    kernel: bool,
+    /// The address space of the pages, known from the first one added.
+    page_table: Option<PhysAddr>,
+    pages: [VirtAddr; MAX_BATCH_PAGES],
+    page_count: usize,
+    frames: Vec<PhysFrame>,
+}
+
+impl TlbBatch {
+    /// A batch for one user address space.
+    pub fn new() -> Self {
+        Self {
+            kernel: false,
+            page_table: None,
+            pages: [VirtAddr::zero(); MAX_BATCH_PAGES],
+            page_count: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    /// A batch for the kernel half, which every CPU may cache.
+    pub fn kernel() -> Self {
+        let mut batch = Self::new();
+        batch.kernel = true;
+        batch
+    }
+
+    pub fn add(&mut self, page_table: &OffsetPageTable, page: Page) {
+        let page_table = page_table.physical_address();
+        debug_assert!(self.kernel || self.page_table.map_or(true, |other| other == page_table));
+        self.page_table = Some(page_table);
+
+        if let Some(slot) = self.pages.get_mut(self.page_count) {
+            *slot = page.start_address();
+        }
+        self.page_count += 1;
+    }
+
+    /// Frees `frame` after the flush. Must not be called while holding
+    /// `FRAME_ALLOCATOR`, as it may allocate.
+    pub fn free_after_flush(&mut self, frame: PhysFrame) {
+        self.frames.push(frame);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.page_count == 0 && self.frames.is_empty()
+    }
+
+    /// Same as dropping the batch, spelled out at call sites.
+    pub fn flush(self) {}
+
+    fn flush_local(&self) {
+        let current = Cr3::read().0.start_address();
+        if self.kernel || self.page_table == Some(current) {
+            let pages = self.pages.iter().copied().take(self.page_count);
+            flush_pages(pages, self.page_count, self.kernel);
+        } else if let Some(page_table) = self.page_table {
+            current_cpu().pcids().forget(page_table);
+        }
+    }
+
+    fn shoot_down(&self) {
+        let cpu_id = current_cpu_id();
+        let _guard = lock_shootdown(cpu_id);
+
+        for (slot, page) in SHOOTDOWN.pages.iter().zip(self.pages.iter()) {
+            slot.store(page.as_u64(), Ordering::Relaxed);
+        }
+        SHOOTDOWN
+            .page_count
+            .store(self.page_count, Ordering::Relaxed);
+        SHOOTDOWN.kernel.store(self.kernel, Ordering::Relaxed);
+
+        // The page table changes must be visible before looking at which
+        // CPUs use it. A CPU that switches to it later either reloads CR3
+        // or finds its entries marked stale.
+        fence(Ordering::SeqCst);
+
+        let cpus = CPUS.read();
+        for &other in cpus.iter_id().filter(|&&other| other != cpu_id) {
+            let info = cpus.get(other);
+            // They flush everything once they join, see `join_shootdowns`.
+            if !info.is_online() {
+                continue;
+            }
+            // Kernel entries are cached by every CPU, user ones only by CPUs
+            // in that address space or with its PCID.
+            if !self.kernel && self.page_table != info.active_page_table() {
+                let Some(page_table) = self.page_table else {
+                    continue;
+                };
+                info.pcids().forget(page_table);
+                // The CPU may have switched to the page table and kept its
+                // entries before they were marked stale.
+                if info.active_page_table() != Some(page_table) {
+                    continue;
+                }
+            }
+            SHOOTDOWN.remaining.fetch_add(1, Ordering::AcqRel);
+            info.request_tlb_flush();
+            apic::send_ipi(InterruptIndex::TlbShootdown, other);
+        }
+        drop(cpus);
+
+        while SHOOTDOWN.remaining.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+
+        interrupts::without_interrupts(|| {
+            if self.page_count != 0 {
+                self.flush_local();
+                self.shoot_down();
+            }
+
+            if !self.frames.is_empty() {
+                let mut frame_allocator = FRAME_ALLOCATOR.lock();
+                for frame in self.frames.drain(..) {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+    }
+}
+
+/// Makes shootdowns reach this CPU from now on, just before it enables
+/// interrupts for the first time. What it cached before, while they skipped
+/// it, is flushed.
+pub fn join_shootdowns() {
+    current_cpu().set_online();
+    fence(Ordering::SeqCst);
+    flush_all_address_spaces();
+}
+
+/// Takes the shootdown lock, serving requests aimed at this CPU while
+/// waiting so that two CPUs shooting at each other do not deadlock.
+fn lock_shootdown(cpu_id: u32) -> MutexGuard<'static, ()> {
+    loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            return guard;
+        }
+        handle_shootdown(cpu_id);
+        core::hint::spin_loop();
+    }
+}
+
+/// Serves a TLB shootdown requested from this CPU, if any.
+pub fn handle_shootdown(cpu_id: u32) {
+    if !CPUS.read().get(cpu_id).take_tlb_flush_request() {
+        return;
+    }
+
+    let page_count = SHOOTDOWN.page_count.load(Ordering::Relaxed);
+    let pages = SHOOTDOWN
+        .pages
+        .iter()
+        .take(page_count)
+        .map(|page| VirtAddr::new(page.load(Ordering::Relaxed)));
+    flush_pages(pages, page_count, SHOOTDOWN.kernel.load(Ordering::Relaxed));
+
+    SHOOTDOWN.remaining.fetch_sub(1, Ordering::AcqRel);
+}
@@ -0,0 +1,603 @@
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use core::ops::Range;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, MapperFlush, TranslateResult};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{FrameDeallocator, PageTable, PhysFrame};
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Translate};
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::ExtendedPageTable;
+use super::{convert_physical_to_virtual, BuddyFrameAllocator, FrameUsage, FRAME_ALLOCATOR};
+use super::{TlbBatch, BORROWED_FRAME, COPY_ON_WRITE, USER_TABLE_FLAGS};
+use crate::error::{RcError, RcResult};
+use crate::object::Vmo;
+
+pub const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Everything from here upwards belongs to the kernel.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Mappings without a fixed address are placed in this range.
+pub const USER_MMAP_START: u64 = 0x2000_0000_0000;
+pub const USER_MMAP_END: u64 = 0x7000_0000_0000;
+
+/// The kind of access that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+bitflags! {
+    /// Permissions of a user mapping as requested through syscalls.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MmuFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+impl MmuFlags {
+    /// x86_64 cannot map pages that are not readable, so `READ` is required.
+    /// No user page is ever both writable and executable.
+    pub fn page_table_flags(self) -> RcResult<PageTableFlags> {
+        if !self.contains(Self::READ) {
+            return Err(RcError::INVALID_ARGS);
+        }
+        if self.contains(Self::WRITE | Self::EXECUTE) {
+            return Err(RcError::ACCESS_DENIED);
+        }
+
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.contains(Self::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Self::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        Ok(flags)
+    }
+}
+
+#[derive(Clone)]
+pub enum VmaKind {
+    /// Zero-filled on first touch.
+    Anonymous,
+    /// Initialized from ELF segment data that stays in memory, the rest of
+    /// the region is zero-filled.
+    Image(Vec<(VirtAddr, &'static [u8])>),
+    /// Grows downwards on faults below it, but never below `limit`.
+    Stack { limit: VirtAddr },
+    /// Shares the pages of a VMO, starting `offset` bytes into it.
+    Vmo { vmo: Arc<Vmo>, offset: usize },
+}
+
+/// A page-aligned region of a user address space.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    /// The most `protect` may grant, limited by the handle it was mapped with.
+    pub max_permissions: MmuFlags,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn new(start: VirtAddr, end: VirtAddr, flags: PageTableFlags, kind: VmaKind) -> Self {
+        Self {
+            start: start.align_down(PAGE_SIZE),
+            end: end.align_up(PAGE_SIZE),
+            flags,
+            max_permissions: MmuFlags::all(),
+            kind,
+        }
+    }
+
+    pub fn with_max_permissions(mut self, max_permissions: MmuFlags) -> Self {
+        self.max_permissions = max_permissions;
+        self
+    }
+
+    #[inline]
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => true,
+            Access::Write => self.flags.contains(PageTableFlags::WRITABLE),
+            Access::Execute => !self.flags.contains(PageTableFlags::NO_EXECUTE),
+        }
+    }
+
+    /// Cuts the region in two at `at`, returning the upper part.
+    pub fn split_off(&mut self, at: VirtAddr) -> Self {
+        let mut kind = self.kind.clone();
+        if let VmaKind::Vmo { offset, .. } = &mut kind {
+            *offset += (at - self.start) as usize;
+        }
+
+        let upper = Self {
+            start: at,
+            end: self.end,
+            flags: self.flags,
+            max_permissions: self.max_permissions,
+            kind,
+        };
+        self.end = at;
+        upper
+    }
+
+    /// Fills a freshly allocated page with the contents it should start with.
+    fn initialize_page(&self, page: Page, buffer: &mut [u8]) {
+        buffer.fill(0);
+
+        let VmaKind::Image(segments) = &self.kind else {
+            return;
+        };
+        let page_start = page.start_address();
+        let page_end = page_start + PAGE_SIZE;
+        for (address, data) in segments.iter() {
+            let data_end = *address + data.len() as u64;
+            let start = page_start.max(*address);
+            let end = page_end.min(data_end);
+            if start >= end {
+                continue;
+            }
+
+            let source = (start - *address) as usize..(end - *address) as usize;
+            let target = (start - page_start) as usize..(end - page_start) as usize;
+            buffer[target].copy_from_slice(&data[source]);
+        }
+    }
+
+    /// Returns the frame to map at `page` and whether the page table owns it.
+    fn frame_for(&self, page: Page) -> RcResult<(PhysFrame, bool)> {
+        if let VmaKind::Vmo { vmo, offset } = &self.kind {
+            let offset = (page.start_address() - self.start) as usize + offset;
+            return Ok((vmo.commit_page(offset / PAGE_SIZE as usize)?, false));
+        }
+
+        let frame = interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR.lock().allocate_for(FrameUsage::User)
+        })
+        .ok_or(RcError::NO_MEMORY)?;
+        let buffer = convert_physical_to_virtual(frame.start_address());
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(buffer.as_mut_ptr::<u8>(), PAGE_SIZE as usize)
+        };
+        self.initialize_page(page, buffer);
+        Ok((frame, true))
+    }
+}
+
+/// The regions a process may access, keyed by their start address.
+#[derive(Default)]
+pub struct VmaList {
+    vmas: BTreeMap<VirtAddr, Vma>,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> RcResult<()> {
+        if vma.start >= vma.end || self.overlaps(vma.start, vma.end) {
+            return Err(RcError::ALREADY_EXISTS);
+        }
+        self.vmas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    pub fn find(&self, address: VirtAddr) -> Option<&Vma> {
+        self.vmas
+            .range(..=address)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(address))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.vmas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    /// Finds the lowest gap of `size` bytes in the mmap range.
+    fn find_free(&self, size: u64) -> Option<VirtAddr> {
+        if size > USER_MMAP_END - USER_MMAP_START {
+            return None;
+        }
+
+        let mut candidate = USER_MMAP_START;
+        for vma in self.vmas.values() {
+            if vma.end.as_u64() <= candidate {
+                continue;
+            }
+            if vma.start.as_u64() >= candidate + size {
+                break;
+            }
+            candidate = vma.end.as_u64();
+        }
+        (candidate + size <= USER_MMAP_END).then(|| VirtAddr::new(candidate))
+    }
+
+    /// Adds a region of `size` bytes at `address`, or wherever it fits when
+    /// no address is given. Returns where it was placed.
+    pub fn map(
+        &mut self,
+        address: Option<VirtAddr>,
+        size: u64,
+        flags: PageTableFlags,
+        max_permissions: MmuFlags,
+        kind: VmaKind,
+    ) -> RcResult<VirtAddr> {
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(RcError::INVALID_ARGS);
+        }
+
+        let start = match address {
+            Some(address) => {
+                let end = address.as_u64().checked_add(size);
+                if !address.is_aligned(PAGE_SIZE)
+                    || address.as_u64() < USER_MMAP_START
+                    || end.map_or(true, |end| end > USER_MMAP_END)
+                {
+                    return Err(RcError::INVALID_ARGS);
+                }
+                address
+            }
+            None => self.find_free(size).ok_or(RcError::NO_MEMORY)?,
+        };
+
+        let vma = Vma::new(start, start + size, flags, kind);
+        self.insert(vma.with_max_permissions(max_permissions))?;
+        Ok(start)
+    }
+
+    /// Makes sure no region crosses `at`.
+    fn split_at(&mut self, at: VirtAddr) {
+        let Some((_, vma)) = self.vmas.range_mut(..at).next_back() else {
+            return;
+        };
+        if vma.end > at {
+            let upper = vma.split_off(at);
+            self.vmas.insert(at, upper);
+        }
+    }
+
+    /// Removes every region between `start` and `end`, cutting the ones
+    /// that stick out, and frees the pages they had mapped once `batch` is
+    /// flushed.
+    pub fn unmap(
+        &mut self,
+        page_table: &mut OffsetPageTable<'static>,
+        start: VirtAddr,
+        end: VirtAddr,
+        batch: &mut TlbBatch,
+    ) {
+        self.split_at(start);
+        self.split_at(end);
+
+        let starts = self
+            .vmas
+            .range(start..end)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in starts {
+            let vma = self.vmas.remove(&start).unwrap();
+            unmap_pages(page_table, vma.start, vma.end, batch);
+        }
+    }
+
+    /// Changes the permissions of a range that regions have to cover
+    /// entirely.
+    pub fn protect(
+        &mut self,
+        page_table: &mut OffsetPageTable<'static>,
+        start: VirtAddr,
+        end: VirtAddr,
+        permissions: MmuFlags,
+        batch: &mut TlbBatch,
+    ) -> RcResult<()> {
+        let flags = permissions.page_table_flags()?;
+
+        let mut covered = start;
+        for (_, vma) in self.vmas.range(..end) {
+            if vma.end <= covered {
+                continue;
+            }
+            if vma.start > covered {
+                break;
+            }
+            if !vma.max_permissions.contains(permissions) {
+                return Err(RcError::ACCESS_DENIED);
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return Err(RcError::NOT_FOUND);
+        }
+
+        self.split_at(start);
+        self.split_at(end);
+        for (_, vma) in self.vmas.range_mut(start..end) {
+            vma.flags = flags;
+            update_page_flags(page_table, vma.start, vma.end, flags, batch);
+        }
+        Ok(())
+    }
+
+    /// Extends a stack region downwards to cover `address`, keeping at least
+    /// one unmapped guard page between it and the region below.
+    fn grow_stack(&mut self, address: VirtAddr) -> Option<()> {
+        let (&start, stack) = self.vmas.range(address..).next()?;
+        let VmaKind::Stack { limit } = stack.kind else {
+            return None;
+        };
+        let new_start = address.align_down(PAGE_SIZE);
+        if new_start < limit || self.overlaps(new_start - PAGE_SIZE, start) {
+            return None;
+        }
+
+        let mut stack = self.vmas.remove(&start).unwrap();
+        stack.start = new_start;
+        self.vmas.insert(new_start, stack);
+        Some(())
+    }
+
+    /// Copies the regions and the mappings of their faulted-in pages into
+    /// `child_table`. Private pages end up shared by both tables, and
+    /// writable ones become copy-on-write in both.
+    pub fn fork(
+        &self,
+        page_table: &mut OffsetPageTable<'static>,
+        child_table: &mut OffsetPageTable<'static>,
+        batch: &mut TlbBatch,
+    ) -> RcResult<VmaList> {
+        let page_table = &*page_table;
+        for vma in self.vmas.values() {
+            for_each_mapped(page_table, vma.start, vma.end, |page, entry| {
+                let frame = PhysFrame::containing_address(entry.addr());
+                let mut flags = entry.flags();
+                if !flags.contains(BORROWED_FRAME) {
+                    BuddyFrameAllocator::share_frame(frame);
+                    if flags.contains(PageTableFlags::WRITABLE) {
+                        flags.remove(PageTableFlags::WRITABLE);
+                        flags.insert(COPY_ON_WRITE);
+                        entry.set_flags(flags);
+                        batch.add(page_table, page);
+                    }
+                }
+
+                let result = interrupts::without_interrupts(|| {
+                    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+                    unsafe { map_user_page(child_table, page, frame, flags, &mut frame_allocator) }
+                });
+                match result {
+                    Ok(flush) => {
+                        flush.ignore();
+                        Ok(())
+                    }
+                    Err(_) => {
+                        if !flags.contains(BORROWED_FRAME) {
+                            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                        }
+                        Err(RcError::NO_MEMORY)
+                    }
+                }
+            })?;
+        }
+
+        Ok(VmaList {
+            vmas: self.vmas.clone(),
+        })
+    }
+
+    /// Maps the page containing `address` if some region allows `access`.
+    /// Breaking copy-on-write sharing replaces a mapping, which other CPUs
+    /// drop when `batch` is flushed.
+    pub fn handle_page_fault(
+        &mut self,
+        page_table: &mut OffsetPageTable<'static>,
+        address: VirtAddr,
+        access: Access,
+        batch: &mut TlbBatch,
+    ) -> RcResult<()> {
+        if self.find(address).is_none() {
+            self.grow_stack(address).ok_or(RcError::NOT_FOUND)?;
+        }
+        let vma = self.find(address).unwrap();
+        if !vma.allows(access) {
+            return Err(RcError::ACCESS_DENIED);
+        }
+
+        let page = Page::<Size4KiB>::containing_address(address);
+        if let TranslateResult::Mapped { frame, flags, .. } =
+            page_table.translate(page.start_address())
+        {
+            if access == Access::Write && flags.contains(COPY_ON_WRITE) {
+                let MappedFrame::Size4KiB(frame) = frame else {
+                    return Err(RcError::NOT_SUPPORTED);
+                };
+                return copy_on_write(page_table, page, frame, vma.flags, batch);
+            }
+
+            // Another thread may have mapped the page in the meantime.
+            tlb::flush(page.start_address());
+            return Ok(());
+        }
+
+        let (frame, owned) = vma.frame_for(page)?;
+        let flags = match owned {
+            true => vma.flags,
+            false => vma.flags | BORROWED_FRAME,
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            match unsafe { map_user_page(page_table, page, frame, flags, &mut frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(())
+                }
+                Err(_) => {
+                    if owned {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                    Err(RcError::NO_MEMORY)
+                }
+            }
+        })
+    }
+}
+
+/// Maps a user page with intermediate tables that leave permissions to
+/// the leaf entry.
+unsafe fn map_user_page(
+    page_table: &mut OffsetPageTable<'static>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+    page_table.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frame_allocator)
+}
+
+/// Gives the page its own copy of a frame shared by a fork, or takes the
+/// frame over if everyone else has dropped it already.
+fn copy_on_write(
+    page_table: &mut OffsetPageTable<'static>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    batch: &mut TlbBatch,
+) -> RcResult<()> {
+    if !BuddyFrameAllocator::is_frame_shared(frame) {
+        let flush = unsafe { page_table.update_flags(page, flags) };
+        flush.map_err(|_| RcError::BAD_STATE)?.flush();
+        return Ok(());
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let copy = frame_allocator
+            .allocate_for(FrameUsage::User)
+            .ok_or(RcError::NO_MEMORY)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                convert_physical_to_virtual(frame.start_address()).as_ptr::<u8>(),
+                convert_physical_to_virtual(copy.start_address()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            );
+        }
+
+        let (frame, flush) = page_table.unmap(page).map_err(|_| RcError::BAD_STATE)?;
+        flush.ignore();
+        batch.add(page_table, page);
+
+        unsafe { map_user_page(page_table, page, copy, flags, &mut frame_allocator) }
+            .map_err(|_| RcError::NO_MEMORY)?
+            .ignore();
+        Ok(frame)
+    })
+    .map(|frame| batch.free_after_flush(frame))
+}
+
+/// Calls `f` with the page and entry of every 4 KiB page mapped between
+/// `start` and `end`, skipping the tables that were never allocated.
+/// Stops at the first error `f` returns.
+fn for_each_mapped(
+    page_table: &OffsetPageTable<'static>,
+    start: VirtAddr,
+    end: VirtAddr,
+    mut f: impl FnMut(Page, &mut PageTableEntry) -> RcResult<()>,
+) -> RcResult<()> {
+    let range = start.as_u64()..end.as_u64();
+    unsafe { walk_user_table(page_table.physical_address(), 4, 0, &range, &mut f) }
+}
+
+/// Visits the entries of the table at `level` that cover part of `range`,
+/// where the first one starts at `base`.
+unsafe fn walk_user_table(
+    physical_address: PhysAddr,
+    level: u8,
+    base: u64,
+    range: &Range<u64>,
+    f: &mut impl FnMut(Page, &mut PageTableEntry) -> RcResult<()>,
+) -> RcResult<()> {
+    let table = &mut *convert_physical_to_virtual(physical_address).as_mut_ptr::<PageTable>();
+    let entry_size = PAGE_SIZE << (9 * (level - 1));
+    let first = (range.start.max(base) - base) / entry_size;
+    let last = (range.end - base).div_ceil(entry_size).min(512);
+
+    for index in first..last {
+        let entry = &mut table[index as usize];
+        let address = base + index * entry_size;
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        if level == 1 {
+            f(Page::containing_address(VirtAddr::new(address)), entry)?;
+        } else if !flags.contains(PageTableFlags::HUGE_PAGE) {
+            walk_user_table(entry.addr(), level - 1, address, range, f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps the pages that were faulted in between `start` and `end`.
+fn unmap_pages(
+    page_table: &mut OffsetPageTable<'static>,
+    start: VirtAddr,
+    end: VirtAddr,
+    batch: &mut TlbBatch,
+) {
+    let page_table = &*page_table;
+    let _ = for_each_mapped(page_table, start, end, |page, entry| {
+        let frame = PhysFrame::containing_address(entry.addr());
+        let flags = entry.flags();
+        entry.set_unused();
+        batch.add(page_table, page);
+        if !flags.contains(BORROWED_FRAME) {
+            batch.free_after_flush(frame);
+        }
+        Ok(())
+    });
+}
+
+fn update_page_flags(
+    page_table: &mut OffsetPageTable<'static>,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+    batch: &mut TlbBatch,
+) {
+    let page_table = &*page_table;
+    let _ = for_each_mapped(page_table, start, end, |page, entry| {
+        // Pages still shared by a fork stay read-only until copied.
+        let mut flags = flags | (entry.flags() & (BORROWED_FRAME | COPY_ON_WRITE));
+        if flags.contains(COPY_ON_WRITE) {
+            flags.remove(PageTableFlags::WRITABLE);
+        }
+        entry.set_flags(flags);
+        batch.add(page_table, page);
+        Ok(())
+    });
+}
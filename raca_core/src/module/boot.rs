@@ -0,0 +1,23 @@
+use core::{ffi::CStr, slice};
+use limine::{modules::InternalModule, request::ModuleRequest};
+
+const fn hello_kernel_module_path() -> &'static [u8] {
+    &[b'/', b'h', b'e', b'l', b'l', b'o', b'.', b'k', b'm', 0]
+}
+
+static HELLO_MODULE: InternalModule = InternalModule::new()
+    .with_path(unsafe { CStr::from_bytes_with_nul_unchecked(hello_kernel_module_path()) });
+
+#[used]
+#[link_section = ".requests"]
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new().with_internal_modules(&[&HELLO_MODULE]);
+
+/// Looks up a file loaded by the bootloader, e.g. `/hello.km`.
+pub fn get_boot_file(path: &str) -> Option<&'static [u8]> {
+    let response = MODULE_REQUEST.get_response()?;
+    let file = response
+        .modules()
+        .iter()
+        .find(|file| file.path().ends_with(path.as_bytes()))?;
+    Some(unsafe { slice::from_raw_parts(file.addr(), file.size() as usize) })
+}
@@ -1,19 +1,18 @@
-use core::{alloc::Layout, mem::transmute};
+use core::mem::transmute;
 
 use alloc::{
-    alloc::alloc,
     collections::btree_map::BTreeMap,
     string::{String, ToString},
 };
 use goblin::elf::Elf;
-use x86_64::{
-    structures::paging::{Mapper, Page, Size4KiB},
-    VirtAddr,
-};
+use x86_64::VirtAddr;
 
-use crate::memory::{ExtendedPageTable, MappingType, KERNEL_PAGE_TABLE};
+use crate::memory::{FrameUsage, KernelArea, MappingType};
 use operations::*;
 
+pub use boot::*;
+
+mod boot;
 mod operations;
 
 #[repr(C)]
@@ -22,6 +21,8 @@ pub struct InfoStruct {
 }
 
 pub struct Module {
+    /// Where the sections are loaded, kept for as long as the module lives.
+    _image: KernelArea,
     name: String,
     symbol_addresses: BTreeMap<String, u64>,
     entry_address: u64,
@@ -30,28 +31,26 @@ pub struct Module {
 impl Module {
     pub fn load(data: &[u8]) -> Self {
         let binary = Elf::parse(data).unwrap();
-        let base = unsafe { alloc(Layout::from_size_align(data.len(), 4096).unwrap()) } as u64;
+
+        let image_size = binary
+            .section_headers
+            .iter()
+            .filter(|section| !section.is_relocation())
+            .map(|section| section.sh_size as usize)
+            .sum::<usize>()
+            .max(data.len());
+        let image = KernelArea::new(
+            image_size,
+            MappingType::KernelCode.flags(),
+            FrameUsage::Module,
+        )
+        .unwrap();
+        let base = image.start_address().as_u64();
 
         let mut section_addresses = BTreeMap::new();
 
         let mut current_adress = base;
 
-        let mut page_table = KERNEL_PAGE_TABLE.lock();
-
-        let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(base));
-        let end_page =
-            Page::<Size4KiB>::containing_address(VirtAddr::new(base + data.len() as u64));
-
-        for page in start_page..=end_page {
-            //log::info!("mapping {:x?}",page);
-            unsafe {
-                page_table
-                    .update_flags(page, MappingType::KernelCode.flags())
-                    .unwrap()
-                    .flush();
-            }
-        }
-
         for (id, section) in binary.section_headers.iter().enumerate() {
             if section.is_relocation() {
                 continue;
@@ -61,13 +60,26 @@ impl Module {
             current_adress += section.sh_size;
         }
 
+        for (&section_id, &section_address) in section_addresses.iter() {
+            let section = &binary.section_headers[section_id];
+
+            let section_data = &data[section.sh_offset as usize
+                ..section.sh_offset as usize + section.sh_size as usize];
+            unsafe {
+                (section_address as *mut u8)
+                    .copy_from_nonoverlapping(section_data.as_ptr(), section_data.len());
+            }
+        }
+
+        // Relocated in the loaded copy, the boot file stays as it is.
         for (section_index, relocation_section) in binary.shdr_relocs {
 
             let relocationed_section = binary
                 .section_headers
                 .get(section_index)
-                .unwrap();
-            let relocationed_section = binary.section_headers.get(relocationed_section.sh_info as usize).unwrap();
+                .unwrap()
+                .sh_info as usize;
+            let relocationed_address = *section_addresses.get(&relocationed_section).unwrap();
 
             for relocation in relocation_section.iter() {
                 let symbol = binary.syms.get(relocation.r_sym).unwrap();
@@ -82,7 +94,6 @@ impl Module {
                 } else {
                     *section_addresses.get(&symbol.st_shndx).unwrap()
                 };
-                let offset = relocation.r_offset + relocationed_section.sh_offset;
                 let addend = relocation.r_addend.unwrap_or(0);
 
                 let target_address = VirtAddr::new(if addend >= 0 {
@@ -92,22 +103,12 @@ impl Module {
                 });
 
                 unsafe {
-                    ((data.as_ptr() as u64 + offset as u64) as *mut u64)
-                        .write(target_address.as_u64());
+                    ((relocationed_address + relocation.r_offset) as *mut u64)
+                        .write_unaligned(target_address.as_u64());
                 }
             }
         }
 
-        for (&section_id, &section_address) in section_addresses.iter() {
-            let section = &binary.section_headers[section_id];
-
-            page_table.write_to_mapped_address(
-                &data[section.sh_offset as usize
-                    ..section.sh_offset as usize + section.sh_size as usize],
-                VirtAddr::new(section_address),
-            );
-        }
-
         let symbol_addresses = binary
             .syms
             .iter()
@@ -127,6 +128,7 @@ impl Module {
         let name = module_info.name;
 
         Self {
+            _image: image,
             name: name.into(),
             symbol_addresses,
             entry_address,
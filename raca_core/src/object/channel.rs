@@ -0,0 +1,132 @@
+use alloc::{
+    collections::vec_deque::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+
+use super::{Handle, KObjectBase, KernelObject, ObjectType, Signals};
+use crate::error::{RcError, RcResult};
+
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+pub const MAX_MESSAGE_HANDLES: usize = 64;
+const MAX_PENDING_MESSAGES: usize = 64;
+
+pub struct MessagePacket {
+    pub data: Vec<u8>,
+    pub handles: Vec<Handle>,
+}
+
+/// One endpoint of a bidirectional message pipe.
+pub struct Channel {
+    base: KObjectBase,
+    peer: Weak<Channel>,
+    messages: Mutex<VecDeque<MessagePacket>>,
+}
+
+impl Channel {
+    pub fn create() -> (Arc<Self>, Arc<Self>) {
+        let mut end1 = None;
+        let end0 = Arc::new_cyclic(|end0| {
+            let peer = Arc::new(Self::with_peer(end0.clone()));
+            let channel = Self::with_peer(Arc::downgrade(&peer));
+            end1 = Some(peer);
+            channel
+        });
+        (end0, end1.unwrap())
+    }
+
+    fn with_peer(peer: Weak<Channel>) -> Self {
+        Self {
+            base: KObjectBase::with_signals(Signals::WRITABLE),
+            peer,
+            messages: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Whether `object` is this endpoint or its peer. Sending either through
+    /// the channel would keep both endpoints alive forever.
+    pub fn is_endpoint(&self, object: &Arc<dyn KernelObject>) -> bool {
+        let object = Arc::as_ptr(object) as *const ();
+        object == self as *const Self as *const () || object == self.peer.as_ptr() as *const ()
+    }
+
+    /// Queues a message on the peer endpoint.
+    pub fn write(&self, message: MessagePacket) -> RcResult<()> {
+        let peer = self.peer.upgrade().ok_or(RcError::PEER_CLOSED)?;
+        peer.push(message, &self.base)
+    }
+
+    /// Dequeues the oldest message, letting `check` reject it (e.g. because
+    /// the caller's buffers are too small) while leaving it queued.
+    pub fn read(
+        &self,
+        check: impl FnOnce(&MessagePacket) -> RcResult<()>,
+    ) -> RcResult<MessagePacket> {
+        let mut messages = self.messages.lock();
+
+        let message = match messages.front() {
+            Some(message) => message,
+            None if self.peer.strong_count() == 0 => return Err(RcError::PEER_CLOSED),
+            None => return Err(RcError::SHOULD_WAIT),
+        };
+        check(message)?;
+
+        let message = messages.pop_front().unwrap();
+        if messages.is_empty() {
+            self.base.signal_clear(Signals::READABLE);
+        }
+        if let Some(peer) = self.peer.upgrade() {
+            peer.base.signal_set(Signals::WRITABLE);
+        }
+
+        Ok(message)
+    }
+
+    /// Puts a message taken by `read` back in front, e.g. because it could
+    /// not be handed to the reader.
+    pub fn unread(&self, message: MessagePacket) {
+        let mut messages = self.messages.lock();
+        messages.push_front(message);
+        if messages.len() >= MAX_PENDING_MESSAGES {
+            if let Some(peer) = self.peer.upgrade() {
+                peer.base.signal_clear(Signals::WRITABLE);
+            }
+        }
+        self.base.signal_set(Signals::READABLE);
+    }
+
+    fn push(&self, message: MessagePacket, writer: &KObjectBase) -> RcResult<()> {
+        let mut messages = self.messages.lock();
+        if messages.len() >= MAX_PENDING_MESSAGES {
+            return Err(RcError::SHOULD_WAIT);
+        }
+
+        messages.push_back(message);
+        if messages.len() == MAX_PENDING_MESSAGES {
+            writer.signal_clear(Signals::WRITABLE);
+        }
+        self.base.signal_set(Signals::READABLE);
+
+        Ok(())
+    }
+}
+
+impl KernelObject for Channel {
+    fn base(&self) -> KObjectBase {
+        self.base.clone()
+    }
+
+    fn object_type(&self) -> ObjectType {
+        ObjectType::Channel
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if let Some(peer) = self.peer.upgrade() {
+            peer.base
+                .signal_change(Signals::WRITABLE, Signals::PEER_CLOSED);
+        }
+    }
+}
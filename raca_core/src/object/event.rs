@@ -0,0 +1,30 @@
+use alloc::sync::Arc;
+
+use super::{KObjectBase, KernelObject, ObjectType, Signals};
+
+/// A plain object whose only state is its signals.
+pub struct Event {
+    base: KObjectBase,
+}
+
+impl Event {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            base: KObjectBase::new(),
+        })
+    }
+}
+
+impl KernelObject for Event {
+    fn base(&self) -> KObjectBase {
+        self.base.clone()
+    }
+
+    fn object_type(&self) -> ObjectType {
+        ObjectType::Event
+    }
+
+    fn allowed_user_signals(&self) -> Signals {
+        Signals::USER_ALL | Signals::SIGNALED
+    }
+}
@@ -0,0 +1,143 @@
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+
+use super::{KernelObject, Rights};
+use crate::error::{RcError, RcResult};
+
+pub type HandleValue = u32;
+
+pub const INVALID_HANDLE: HandleValue = 0;
+
+/// A reference to a kernel object together with what it may be used for.
+#[derive(Clone)]
+pub struct Handle {
+    pub object: Arc<dyn KernelObject>,
+    pub rights: Rights,
+}
+
+impl Handle {
+    pub fn new(object: Arc<dyn KernelObject>, rights: Rights) -> Self {
+        Self { object, rights }
+    }
+}
+
+pub struct HandleTable {
+    handles: BTreeMap<HandleValue, Handle>,
+    next_value: HandleValue,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self {
+            handles: BTreeMap::new(),
+            next_value: INVALID_HANDLE + 1,
+        }
+    }
+
+    pub fn add(&mut self, handle: Handle) -> HandleValue {
+        let value = self.next_value;
+        self.next_value += 1;
+        self.handles.insert(value, handle);
+        value
+    }
+
+    pub fn remove(&mut self, value: HandleValue) -> RcResult<Handle> {
+        self.handles.remove(&value).ok_or(RcError::BAD_HANDLE)
+    }
+
+    pub fn get_handle(&self, value: HandleValue) -> RcResult<Handle> {
+        self.handles.get(&value).cloned().ok_or(RcError::BAD_HANDLE)
+    }
+
+    /// Removes a handle which is about to leave this table, checking that
+    /// it carries the `TRANSFER` right first.
+    pub fn take_for_transfer(&mut self, value: HandleValue) -> RcResult<Handle> {
+        let handle = self.handles.get(&value).ok_or(RcError::BAD_HANDLE)?;
+        if !handle.rights.contains(Rights::TRANSFER) {
+            return Err(RcError::ACCESS_DENIED);
+        }
+        self.remove(value)
+    }
+
+    /// Removes a batch of handles for transfer. Either every handle is
+    /// removed or, if any of them is invalid, none are.
+    pub fn take_many_for_transfer(&mut self, values: &[HandleValue]) -> RcResult<Vec<Handle>> {
+        for (index, value) in values.iter().enumerate() {
+            let handle = self.handles.get(value).ok_or(RcError::BAD_HANDLE)?;
+            if !handle.rights.contains(Rights::TRANSFER) {
+                return Err(RcError::ACCESS_DENIED);
+            }
+            if values[..index].contains(value) {
+                return Err(RcError::BAD_HANDLE);
+            }
+        }
+
+        Ok(values
+            .iter()
+            .map(|value| self.handles.remove(value).unwrap())
+            .collect())
+    }
+
+    pub fn get_dyn_object(
+        &self,
+        value: HandleValue,
+        rights: Rights,
+    ) -> RcResult<Arc<dyn KernelObject>> {
+        let handle = self.handles.get(&value).ok_or(RcError::BAD_HANDLE)?;
+        if !handle.rights.contains(rights) {
+            return Err(RcError::ACCESS_DENIED);
+        }
+        Ok(handle.object.clone())
+    }
+
+    pub fn get_object<T: KernelObject + 'static>(
+        &self,
+        value: HandleValue,
+        rights: Rights,
+    ) -> RcResult<Arc<T>> {
+        self.get_dyn_object(value, rights)?.downcast::<T>()
+    }
+
+    pub fn duplicate(&mut self, value: HandleValue, rights: Rights) -> RcResult<HandleValue> {
+        let handle = self.handles.get(&value).ok_or(RcError::BAD_HANDLE)?;
+        if !handle.rights.contains(Rights::DUPLICATE) {
+            return Err(RcError::ACCESS_DENIED);
+        }
+
+        let rights = if rights == Rights::SAME_RIGHTS {
+            handle.rights
+        } else if handle.rights.contains(rights) {
+            rights
+        } else {
+            return Err(RcError::INVALID_ARGS);
+        };
+
+        let handle = Handle::new(handle.object.clone(), rights);
+        Ok(self.add(handle))
+    }
+
+    /// Copies the table for a forked process. Handles to `parent` refer to
+    /// `child` in the copy.
+    pub fn fork<T: KernelObject + 'static>(&self, parent: &Arc<T>, child: &Arc<T>) -> Self {
+        let parent = Arc::as_ptr(parent) as *const u8;
+        let handles = self
+            .handles
+            .iter()
+            .map(|(value, handle)| {
+                let mut handle = handle.clone();
+                if Arc::as_ptr(&handle.object) as *const u8 == parent {
+                    handle.object = child.clone();
+                }
+                (*value, handle)
+            })
+            .collect();
+
+        Self {
+            handles,
+            next_value: self.next_value,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.handles.clear();
+    }
+}
@@ -0,0 +1,132 @@
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::error::{RcError, RcResult};
+use crate::task::WaitQueue;
+
+mod channel;
+mod event;
+mod handle;
+mod rights;
+mod signal;
+mod vmo;
+
+pub use channel::*;
+pub use event::*;
+pub use handle::*;
+pub use rights::*;
+pub use signal::*;
+pub use vmo::*;
+
+/// The unique id of a kernel object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KoId(pub u64);
+
+impl KoId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        KoId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ObjectType {
+    Process = 1,
+    Thread = 2,
+    Vmo = 3,
+    Channel = 4,
+    Event = 5,
+}
+
+pub trait AsAny {
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+impl<T: Any + Send + Sync> AsAny for T {
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// Common interface of everything that can be referenced by a handle.
+pub trait KernelObject: AsAny + Send + Sync {
+    fn base(&self) -> KObjectBase;
+    fn object_type(&self) -> ObjectType;
+
+    /// Signals which user space is allowed to set or clear on this object.
+    fn allowed_user_signals(&self) -> Signals {
+        Signals::USER_ALL
+    }
+}
+
+impl dyn KernelObject {
+    pub fn downcast<T: KernelObject + 'static>(self: Arc<Self>) -> RcResult<Arc<T>> {
+        self.into_any()
+            .downcast::<T>()
+            .map_err(|_| RcError::WRONG_TYPE)
+    }
+}
+
+/// The state shared by every kernel object, cheap to clone out of
+/// objects that live behind a lock.
+#[derive(Clone)]
+pub struct KObjectBase {
+    inner: Arc<KObjectBaseInner>,
+}
+
+struct KObjectBaseInner {
+    id: KoId,
+    signals: Mutex<Signals>,
+    waiters: WaitQueue,
+}
+
+impl KObjectBase {
+    pub fn new() -> Self {
+        Self::with_signals(Signals::empty())
+    }
+
+    pub fn with_signals(signals: Signals) -> Self {
+        let inner = KObjectBaseInner {
+            id: KoId::new(),
+            signals: Mutex::new(signals),
+            waiters: WaitQueue::new(),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn id(&self) -> KoId {
+        self.inner.id
+    }
+
+    pub fn signals(&self) -> Signals {
+        *self.inner.signals.lock()
+    }
+
+    /// Threads waiting for this object's signals to change.
+    pub fn wait_queue(&self) -> &WaitQueue {
+        &self.inner.waiters
+    }
+
+    pub fn signal_change(&self, clear: Signals, set: Signals) {
+        {
+            let mut signals = self.inner.signals.lock();
+            signals.remove(clear);
+            signals.insert(set);
+        }
+        self.inner.waiters.wake_all();
+    }
+
+    pub fn signal_set(&self, set: Signals) {
+        self.signal_change(Signals::empty(), set);
+    }
+
+    pub fn signal_clear(&self, clear: Signals) {
+        self.signal_change(clear, Signals::empty());
+    }
+}
@@ -0,0 +1,59 @@
+use bitflags::bitflags;
+
+bitflags! {
+    /// Operations a handle is allowed to perform on its object.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Rights: u32 {
+        const DUPLICATE = 1 << 0;
+        const TRANSFER = 1 << 1;
+        const READ = 1 << 2;
+        const WRITE = 1 << 3;
+        const EXECUTE = 1 << 4;
+        const MAP = 1 << 5;
+        const GET_PROPERTY = 1 << 6;
+        const SET_PROPERTY = 1 << 7;
+        const ENUMERATE = 1 << 8;
+        const DESTROY = 1 << 9;
+        const SIGNAL = 1 << 12;
+        const SIGNAL_PEER = 1 << 13;
+        const WAIT = 1 << 14;
+        const INSPECT = 1 << 15;
+        const MANAGE_PROCESS = 1 << 17;
+        const MANAGE_THREAD = 1 << 18;
+
+        /// Only valid as an argument to `handle_duplicate`.
+        const SAME_RIGHTS = 1 << 31;
+
+        const BASIC = Self::TRANSFER.bits()
+            | Self::DUPLICATE.bits()
+            | Self::WAIT.bits()
+            | Self::INSPECT.bits();
+        const IO = Self::READ.bits() | Self::WRITE.bits();
+        const PROPERTY = Self::GET_PROPERTY.bits() | Self::SET_PROPERTY.bits();
+
+        const DEFAULT_PROCESS = Self::BASIC.bits()
+            | Self::IO.bits()
+            | Self::PROPERTY.bits()
+            | Self::ENUMERATE.bits()
+            | Self::DESTROY.bits()
+            | Self::SIGNAL.bits()
+            | Self::MANAGE_PROCESS.bits()
+            | Self::MANAGE_THREAD.bits();
+        const DEFAULT_THREAD = Self::BASIC.bits()
+            | Self::IO.bits()
+            | Self::PROPERTY.bits()
+            | Self::DESTROY.bits()
+            | Self::SIGNAL.bits()
+            | Self::MANAGE_THREAD.bits();
+        const DEFAULT_VMO = Self::BASIC.bits()
+            | Self::IO.bits()
+            | Self::PROPERTY.bits()
+            | Self::MAP.bits()
+            | Self::SIGNAL.bits();
+        const DEFAULT_CHANNEL = (Self::BASIC.bits() & !Self::DUPLICATE.bits())
+            | Self::IO.bits()
+            | Self::SIGNAL.bits()
+            | Self::SIGNAL_PEER.bits();
+        const DEFAULT_EVENT = Self::BASIC.bits() | Self::SIGNAL.bits();
+    }
+}
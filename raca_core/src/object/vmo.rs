@@ -0,0 +1,86 @@
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameDeallocator, PageSize, PhysFrame, Size4KiB};
+
+use super::{KObjectBase, KernelObject, ObjectType};
+use crate::error::{RcError, RcResult};
+use crate::memory::{convert_physical_to_virtual, FrameUsage, FRAME_ALLOCATOR};
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+/// Largest VMO that can be created, 4 GiB.
+pub const MAX_VMO_SIZE: usize = 1 << 32;
+
+/// A chunk of memory that can be mapped into address spaces. Pages are
+/// allocated zeroed on first use and shared by every mapping.
+pub struct Vmo {
+    base: KObjectBase,
+    size: usize,
+    frames: Mutex<BTreeMap<usize, PhysFrame>>,
+}
+
+impl Vmo {
+    pub fn new(size: usize) -> RcResult<Arc<Self>> {
+        if size == 0 || size > MAX_VMO_SIZE {
+            return Err(RcError::INVALID_ARGS);
+        }
+
+        Ok(Arc::new(Self {
+            base: KObjectBase::new(),
+            size: size.next_multiple_of(PAGE_SIZE),
+            frames: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the frame backing the page at `index`, allocating it if it
+    /// has not been touched yet.
+    pub fn commit_page(&self, index: usize) -> RcResult<PhysFrame> {
+        if index >= self.size / PAGE_SIZE {
+            return Err(RcError::OUT_OF_RANGE);
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut frames = self.frames.lock();
+            if let Some(frame) = frames.get(&index) {
+                return Ok(*frame);
+            }
+
+            let frame = FRAME_ALLOCATOR
+                .lock()
+                .allocate_for(FrameUsage::User)
+                .ok_or(RcError::NO_MEMORY)?;
+            let address = convert_physical_to_virtual(frame.start_address());
+            unsafe { core::ptr::write_bytes(address.as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+
+            frames.insert(index, frame);
+            Ok(frame)
+        })
+    }
+}
+
+impl KernelObject for Vmo {
+    fn base(&self) -> KObjectBase {
+        self.base.clone()
+    }
+
+    fn object_type(&self) -> ObjectType {
+        ObjectType::Vmo
+    }
+}
+
+impl Drop for Vmo {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            for frame in self.frames.get_mut().values() {
+                unsafe { frame_allocator.deallocate_frame(*frame) };
+            }
+        });
+    }
+}
@@ -0,0 +1,216 @@
+//! Checks that run at boot in kernels built with the `self-test` feature.
+//! A failed check panics.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::arch::smp::{current_cpu_id, CPUS};
+use crate::device::hpet::HPET;
+use crate::memory::{convert_physical_to_virtual, memory_stats, Access, MmuFlags, SlabAllocator};
+use crate::memory::{TlbBatch, VmaKind, PAGE_SIZE, USER_MMAP_START};
+use crate::object::{KernelObject, Signals, Vmo};
+use crate::task::SCHEDULER;
+use crate::task::{sleep_until, wait_until, CpuMask, ExitReason, Priority, Process, Thread};
+
+pub fn run() {
+    address_space_teardown();
+    kernel_thread_reaping();
+    kill_running_process();
+    log::info!("Self tests passed");
+}
+
+/// Fills an address space with every kind of user page, forks it and
+/// breaks some copy-on-write sharing, then checks that dropping both
+/// processes gives every user frame and page table back.
+fn address_space_teardown() {
+    let before = memory_stats();
+    {
+        let mut parent = Process::new("self-test");
+        let base = VirtAddr::new(USER_MMAP_START);
+        let vmo = Vmo::new(2 * PAGE_SIZE as usize).unwrap();
+        let regions = [
+            (
+                0x00000,
+                2,
+                MmuFlags::READ | MmuFlags::EXECUTE,
+                VmaKind::Anonymous,
+            ),
+            (
+                0x10000,
+                4,
+                MmuFlags::READ | MmuFlags::WRITE,
+                VmaKind::Anonymous,
+            ),
+            (
+                0x20000,
+                2,
+                MmuFlags::READ | MmuFlags::WRITE,
+                VmaKind::Vmo { vmo, offset: 0 },
+            ),
+            (
+                0x40000,
+                2,
+                MmuFlags::READ | MmuFlags::WRITE,
+                VmaKind::Stack {
+                    limit: base + 0x30000u64,
+                },
+            ),
+        ];
+
+        let mut batch = TlbBatch::new();
+        let mut pages = Vec::new();
+        for (offset, count, permissions, kind) in regions {
+            let start = base + offset as u64;
+            let flags = permissions.page_table_flags().unwrap();
+            parent
+                .vmas
+                .map(Some(start), count * PAGE_SIZE, flags, permissions, kind)
+                .unwrap();
+
+            let access = match permissions.contains(MmuFlags::WRITE) {
+                true => Access::Write,
+                false => Access::Read,
+            };
+            pages.extend((0..count).map(|index| (start + index * PAGE_SIZE, access)));
+        }
+        // Grows the stack by a page.
+        pages.push((base + 0x3f000u64, Access::Write));
+
+        for &(address, access) in pages.iter() {
+            parent
+                .handle_page_fault(address, access, &mut batch)
+                .unwrap();
+        }
+
+        let mut child = Process::new("self-test");
+        child.vmas = parent
+            .vmas
+            .fork(&mut parent.page_table, &mut child.page_table, &mut batch)
+            .unwrap();
+
+        let data = base + 0x10000u64;
+        child
+            .handle_page_fault(data, Access::Write, &mut TlbBatch::new())
+            .unwrap();
+        parent
+            .handle_page_fault(data + PAGE_SIZE, Access::Write, &mut batch)
+            .unwrap();
+    }
+    let after = memory_stats();
+
+    assert_eq!(before.user, after.user, "User frames leaked");
+    assert_eq!(before.page_tables, after.page_tables, "Page tables leaked");
+}
+
+/// Starts kernel threads pinned to every CPU that return right away, then
+/// checks that the reaper gives all their kernel stacks back.
+fn kernel_thread_reaping() {
+    static RETURNED: AtomicUsize = AtomicUsize::new(0);
+    const THREADS_PER_CPU: usize = 4;
+
+    let before = memory_stats();
+    let masks = CPUS
+        .read()
+        .iter_id()
+        .map(|&cpu_id| CpuMask::single(cpu_id).unwrap_or(CpuMask::ALL))
+        .collect::<Vec<_>>();
+    for &mask in masks.iter() {
+        for _ in 0..THREADS_PER_CPU {
+            Thread::new_kernel_thread(
+                || {
+                    RETURNED.fetch_add(1, Ordering::SeqCst);
+                },
+                Priority::DEFAULT,
+                mask,
+            );
+        }
+    }
+
+    let expected = masks.len() * THREADS_PER_CPU;
+    for _ in 0..1000 {
+        let reaped = memory_stats().kernel_stacks <= before.kernel_stacks;
+        if RETURNED.load(Ordering::SeqCst) == expected && reaped {
+            return;
+        }
+        sleep_until(HPET.elapsed_ns() + 1_000_000).unwrap();
+    }
+    panic!(
+        "{} of {} kernel threads returned, {} kernel stack frames leaked",
+        RETURNED.load(Ordering::SeqCst),
+        expected,
+        memory_stats()
+            .kernel_stacks
+            .saturating_sub(before.kernel_stacks)
+    );
+}
+
+/// Kills a process whose only thread spins in user mode on another CPU,
+/// which has nothing else to run and thus no timer armed, and checks that
+/// the process terminates anyway.
+fn kill_running_process() {
+    let Some((cpu_id, mask)) = CPUS
+        .read()
+        .iter_id()
+        .filter(|&&cpu_id| cpu_id != current_cpu_id())
+        .find_map(|&cpu_id| Some((cpu_id, CpuMask::single(cpu_id)?)))
+    else {
+        return;
+    };
+
+    // `jmp $`
+    let vmo = Vmo::new(PAGE_SIZE as usize).unwrap();
+    let frame = vmo.commit_page(0).unwrap();
+    let code = convert_physical_to_virtual(frame.start_address()).as_mut_ptr::<[u8; 2]>();
+    unsafe { code.write([0xeb, 0xfe]) };
+
+    let process = Arc::new(RwLock::new(Box::new_in(
+        Process::new("self-test"),
+        SlabAllocator,
+    )));
+    let entry = VirtAddr::new(USER_MMAP_START);
+    let permissions = MmuFlags::READ | MmuFlags::EXECUTE;
+    let thread = interrupts::without_interrupts(|| {
+        process
+            .write()
+            .vmas
+            .map(
+                Some(entry),
+                PAGE_SIZE,
+                permissions.page_table_flags().unwrap(),
+                permissions,
+                VmaKind::Vmo { vmo, offset: 0 },
+            )
+            .unwrap();
+        let thread =
+            Thread::new_user_thread(Arc::downgrade(&process), entry.as_u64() as usize, 0).unwrap();
+        SCHEDULER.set_affinity(&thread, mask);
+        thread
+    });
+
+    let running = |thread: &Thread| thread.running && thread.cpu == cpu_id;
+    for _ in 0..1000 {
+        if interrupts::without_interrupts(|| running(&thread.read())) {
+            break;
+        }
+        sleep_until(HPET.elapsed_ns() + 1_000_000).unwrap();
+    }
+    assert!(
+        interrupts::without_interrupts(|| running(&thread.read())),
+        "Spinning thread did not start on CPU {}",
+        cpu_id
+    );
+
+    interrupts::without_interrupts(|| process.write().kill(ExitReason::Killed));
+    let base = process.base();
+    wait_until(
+        &[base.wait_queue()],
+        Some(HPET.elapsed_ns() + 1_000_000_000),
+        || base.signals().contains(Signals::TERMINATED).then_some(()),
+    )
+    .expect("Killed process kept running on another CPU");
+}
@@ -0,0 +1,155 @@
+use alloc::vec::Vec;
+
+use crate::{
+    arch::user::{UserInPtr, UserOutPtr},
+    error::{RcError, RcResult},
+    object::{Channel, Handle, HandleValue, MessagePacket, Rights},
+    object::{MAX_MESSAGE_BYTES, MAX_MESSAGE_HANDLES},
+    task::current_process,
+};
+
+pub fn channel_create(
+    options: u32,
+    mut out0: UserOutPtr<HandleValue>,
+    mut out1: UserOutPtr<HandleValue>,
+) -> RcResult<()> {
+    if options != 0 {
+        return Err(RcError::INVALID_ARGS);
+    }
+
+    let (end0, end1) = Channel::create();
+    let process = current_process();
+    let mut process = process.write();
+    let end0 = process
+        .handles
+        .add(Handle::new(end0, Rights::DEFAULT_CHANNEL));
+    let end1 = process
+        .handles
+        .add(Handle::new(end1, Rights::DEFAULT_CHANNEL));
+    drop(process);
+
+    out0.write(end0)?;
+    out1.write(end1)?;
+    Ok(())
+}
+
+pub fn channel_write(
+    handle: HandleValue,
+    options: u32,
+    bytes: UserInPtr<u8>,
+    num_bytes: usize,
+    handles: UserInPtr<HandleValue>,
+    num_handles: usize,
+) -> RcResult<()> {
+    if options != 0 {
+        return Err(RcError::INVALID_ARGS);
+    }
+    if num_bytes > MAX_MESSAGE_BYTES || num_handles > MAX_MESSAGE_HANDLES {
+        return Err(RcError::OUT_OF_RANGE);
+    }
+
+    let data = bytes.read_array(num_bytes)?;
+    let handle_values = handles.read_array(num_handles)?;
+
+    let process = current_process();
+    let channel = process
+        .read()
+        .handles
+        .get_object::<Channel>(handle, Rights::WRITE)?;
+    let handles = {
+        let mut process = process.write();
+        for &value in handle_values.iter() {
+            if channel.is_endpoint(&process.handles.get_handle(value)?.object) {
+                return Err(RcError::NOT_SUPPORTED);
+            }
+        }
+        process.handles.take_many_for_transfer(&handle_values)?
+    };
+
+    // The transferred handles are consumed even if the write fails.
+    channel.write(MessagePacket { data, handles })
+}
+
+pub fn channel_read(
+    handle: HandleValue,
+    mut bytes: UserOutPtr<u8>,
+    num_bytes: usize,
+    mut handles: UserOutPtr<HandleValue>,
+    num_handles: usize,
+    mut actual: UserOutPtr<usize>,
+) -> RcResult<()> {
+    let process = current_process();
+    let channel = process
+        .read()
+        .handles
+        .get_object::<Channel>(handle, Rights::READ)?;
+
+    // Fault the buffers in first, nothing is copied with the channel
+    // locked. No message is larger than the limits.
+    if !actual.is_null() {
+        actual.check_array(2)?;
+    }
+    if num_bytes != 0 {
+        bytes.check_array(num_bytes.min(MAX_MESSAGE_BYTES))?;
+    }
+    if num_handles != 0 {
+        handles.check_array(num_handles.min(MAX_MESSAGE_HANDLES))?;
+    }
+
+    let mut sizes = [0; 2];
+    let result = channel.read(|message| {
+        sizes = [message.data.len(), message.handles.len()];
+        if sizes[0] > num_bytes || sizes[1] > num_handles {
+            return Err(RcError::BUFFER_TOO_SMALL);
+        }
+        Ok(())
+    });
+    let message = match result {
+        Err(RcError::BUFFER_TOO_SMALL) => {
+            if !actual.is_null() {
+                actual.write_array(&sizes)?;
+            }
+            return Err(RcError::BUFFER_TOO_SMALL);
+        }
+        result => result?,
+    };
+
+    // The message goes back to the channel if it cannot be copied out,
+    // e.g. because another thread unmapped a buffer meanwhile.
+    let written = match actual.is_null() {
+        true => Ok(()),
+        false => actual.write_array(&sizes),
+    };
+    if let Err(err) = written.and_then(|_| bytes.write_array(&message.data)) {
+        channel.unread(message);
+        return Err(err.into());
+    }
+
+    let MessagePacket {
+        data,
+        handles: transferred,
+    } = message;
+    let handle_values = {
+        let mut process = process.write();
+        transferred
+            .into_iter()
+            .map(|handle| process.handles.add(handle))
+            .collect::<Vec<_>>()
+    };
+    if let Err(err) = handles.write_array(&handle_values) {
+        // Other threads may have closed some of them already.
+        let transferred = {
+            let mut process = process.write();
+            handle_values
+                .iter()
+                .filter_map(|&value| process.handles.remove(value).ok())
+                .collect()
+        };
+        channel.unread(MessagePacket {
+            data,
+            handles: transferred,
+        });
+        return Err(err.into());
+    }
+    Ok(())
+}
@@ -8,5 +8,34 @@ numeric_enum! {
     #[allow(clippy::upper_case_acronyms)]
     pub enum SyscallType {
         DEBUG = 0,
+        HANDLE_CLOSE = 1,
+        HANDLE_DUPLICATE = 2,
+        HANDLE_TRANSFER = 3,
+        EVENT_CREATE = 4,
+        OBJECT_SIGNAL = 5,
+        CHANNEL_CREATE = 6,
+        CHANNEL_WRITE = 7,
+        CHANNEL_READ = 8,
+        OBJECT_WAIT_ONE = 9,
+        OBJECT_WAIT_MANY = 10,
+        PROCESS_CREATE = 11,
+        THREAD_CREATE = 12,
+        THREAD_EXIT = 13,
+        PROCESS_EXIT = 14,
+        PROCESS_KILL = 15,
+        PROCESS_JOIN = 16,
+        VMO_CREATE = 17,
+        VM_MAP = 18,
+        VM_UNMAP = 19,
+        VM_PROTECT = 20,
+        PROCESS_FORK = 21,
+        MEM_INFO = 22,
+        MEM_DUMP = 23,
+        THREAD_SET_PRIORITY = 24,
+        THREAD_YIELD = 25,
+        THREAD_SLEEP = 26,
+        THREAD_SLEEP_UNTIL = 27,
+        CLOCK_GET = 28,
+        THREAD_SET_AFFINITY = 29,
     }
 }
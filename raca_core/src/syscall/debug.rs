@@ -1,13 +1,11 @@
-use core::str;
+use crate::{arch::user::UserInPtr, error::RcResult, memory::log_memory_stats};
 
-use crate::error::{RcError, RcResult};
-
-pub fn debug(ptr: usize, len: usize) -> RcResult<()> {
-    let data = unsafe { core::slice::from_raw_parts(ptr as *const u8, len) };
-    crate::print!(
-        "{}",
-        str::from_utf8(data).map_err(|_| RcError::INVALID_ARGS)?
-    );
+pub fn debug(buffer: UserInPtr<u8>, len: usize) -> RcResult<()> {
+    crate::print!("{}", buffer.read_string(len)?);
+    Ok(())
+}
 
+pub fn mem_dump() -> RcResult<()> {
+    log_memory_stats();
     Ok(())
 }
@@ -0,0 +1,48 @@
+use spin::RwLock;
+
+use crate::{
+    arch::user::UserOutPtr,
+    error::{RcError, RcResult},
+    memory::SlabBox,
+    object::{HandleValue, Rights},
+    task::{current_process, Process},
+};
+
+pub fn handle_close(handle: HandleValue) -> RcResult<()> {
+    current_process().write().handles.remove(handle)?;
+    Ok(())
+}
+
+pub fn handle_duplicate(
+    handle: HandleValue,
+    rights: u32,
+    mut out: UserOutPtr<HandleValue>,
+) -> RcResult<()> {
+    let rights = Rights::from_bits_truncate(rights);
+    let new_handle = current_process()
+        .write()
+        .handles
+        .duplicate(handle, rights)?;
+    out.write(new_handle)?;
+    Ok(())
+}
+
+pub fn handle_transfer(
+    handle: HandleValue,
+    process: HandleValue,
+    mut out: UserOutPtr<HandleValue>,
+) -> RcResult<()> {
+    let current = current_process();
+    let target = current
+        .read()
+        .handles
+        .get_object::<RwLock<SlabBox<Process>>>(process, Rights::MANAGE_PROCESS)?;
+    if target.read().is_exiting() {
+        return Err(RcError::BAD_STATE);
+    }
+
+    let handle = current.write().handles.take_for_transfer(handle)?;
+    let new_handle = target.write().handles.add(handle);
+    out.write(new_handle)?;
+    Ok(())
+}
@@ -9,18 +9,61 @@ use x86_64::{
     VirtAddr,
 };
 
-use crate::{arch::gdt::Selectors, error::RcError};
+use crate::{arch::gdt::Selectors, error::RcError, task::exit_if_killed};
 
+mod channel;
 mod consts;
 mod debug;
+mod handle;
+mod object;
+mod task;
+mod vm;
 
+use channel::*;
 use consts::SyscallType as Sys;
 use debug::*;
+use handle::*;
+use object::*;
+use task::*;
+use vm::*;
+
+/// The user registers `asm_syscall_handler` saves at the top of the
+/// kernel stack, lowest address first.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallFrame {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbx: usize,
+    pub rbp: usize,
+    pub rflags: usize,
+    pub rip: usize,
+    _padding: usize,
+    pub rsp: usize,
+}
+
+impl SyscallFrame {
+    /// The frame of the syscall being handled on the given kernel stack.
+    pub fn from_stack_end(stack_end: VirtAddr) -> Self {
+        let address = stack_end - core::mem::size_of::<Self>() as u64;
+        unsafe { address.as_ptr::<Self>().read() }
+    }
+}
 
 #[naked]
 extern "C" fn asm_syscall_handler() {
     unsafe {
         asm!(
+            // Switch to the kernel stack of the current thread, see `CpuInfo`
+            "swapgs",
+            "mov qword ptr gs:[8], rsp",
+            "mov rsp, qword ptr gs:[0]",
+            "push qword ptr gs:[8]",
+            "swapgs",
+            "sub rsp, 8",
+
             "push rcx",
             "push r11",
             "push rbp",
@@ -42,6 +85,9 @@ extern "C" fn asm_syscall_handler() {
             "pop rbp",
             "pop r11",
             "pop rcx",
+
+            "add rsp, 8",
+            "pop rsp",
             "sysretq",
             syscall_matcher = sym syscall_handler,
             options(noreturn)
@@ -100,9 +146,45 @@ pub extern "C" fn syscall_handler(
     );
 
     let ret = match sys_type {
-        Sys::DEBUG => debug(arg1, arg2),
+        Sys::DEBUG => debug(arg1.into(), arg2),
+        Sys::HANDLE_CLOSE => handle_close(arg1 as _),
+        Sys::HANDLE_DUPLICATE => handle_duplicate(arg1 as _, arg2 as _, arg3.into()),
+        Sys::HANDLE_TRANSFER => handle_transfer(arg1 as _, arg2 as _, arg3.into()),
+        Sys::EVENT_CREATE => event_create(arg1 as _, arg2.into()),
+        Sys::OBJECT_SIGNAL => object_signal(arg1 as _, arg2 as _, arg3 as _),
+        Sys::CHANNEL_CREATE => channel_create(arg1 as _, arg2.into(), arg3.into()),
+        Sys::CHANNEL_WRITE => {
+            channel_write(arg1 as _, arg2 as _, arg3.into(), arg4, arg5.into(), arg6)
+        }
+        Sys::CHANNEL_READ => {
+            channel_read(arg1 as _, arg2.into(), arg3, arg4.into(), arg5, arg6.into())
+        }
+        Sys::OBJECT_WAIT_ONE => object_wait_one(arg1 as _, arg2 as _, arg3 as _, arg4.into()),
+        Sys::OBJECT_WAIT_MANY => object_wait_many(arg1.into(), arg2, arg3 as _),
+        Sys::PROCESS_CREATE => process_create(arg1.into(), arg2, arg3.into()),
+        Sys::THREAD_CREATE => thread_create(arg1 as _, arg2, arg3, arg4.into()),
+        Sys::THREAD_EXIT => thread_exit(),
+        Sys::PROCESS_EXIT => process_exit(arg1 as _),
+        Sys::PROCESS_KILL => process_kill(arg1 as _),
+        Sys::PROCESS_JOIN => process_join(arg1 as _, arg2 as _, arg3.into()),
+        Sys::PROCESS_FORK => process_fork(arg1.into()),
+        Sys::VMO_CREATE => vmo_create(arg1, arg2 as _, arg3.into()),
+        Sys::VM_MAP => vm_map(arg1 as _, arg2, arg3, arg4, arg5 as _, arg6.into()),
+        Sys::VM_UNMAP => vm_unmap(arg1, arg2),
+        Sys::VM_PROTECT => vm_protect(arg1, arg2, arg3 as _),
+        Sys::MEM_INFO => mem_info(arg1.into()),
+        Sys::MEM_DUMP => mem_dump(),
+        Sys::THREAD_SET_PRIORITY => thread_set_priority(arg1 as _, arg2),
+        Sys::THREAD_YIELD => thread_yield(),
+        Sys::THREAD_SLEEP => thread_sleep(arg1 as _),
+        Sys::THREAD_SLEEP_UNTIL => thread_sleep_until(arg1 as _),
+        Sys::CLOCK_GET => clock_get(arg1.into()),
+        Sys::THREAD_SET_AFFINITY => thread_set_affinity(arg1 as _, arg2 as _),
     };
 
+    // The process may have been killed while we were in the kernel.
+    exit_if_killed();
+
     match ret {
         Ok(_) => 0,
         Err(err) => err as isize,
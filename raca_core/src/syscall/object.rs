@@ -0,0 +1,114 @@
+use alloc::vec::Vec;
+
+use crate::{
+    arch::user::{UserInOutPtr, UserOutPtr},
+    error::{RcError, RcResult},
+    object::{Event, Handle, HandleValue, KObjectBase, Rights, Signals},
+    task::{current_process, wait_until},
+};
+
+const MAX_WAIT_ITEMS: usize = 64;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WaitItem {
+    handle: HandleValue,
+    wait_for: u32,
+    pending: u32,
+}
+
+/// `u64::MAX` stands for waiting forever.
+pub(super) fn deadline_from(deadline: u64) -> Option<u64> {
+    (deadline != u64::MAX).then_some(deadline)
+}
+
+pub fn event_create(options: u32, mut out: UserOutPtr<HandleValue>) -> RcResult<()> {
+    if options != 0 {
+        return Err(RcError::INVALID_ARGS);
+    }
+
+    let handle = Handle::new(Event::new(), Rights::DEFAULT_EVENT);
+    let handle = current_process().write().handles.add(handle);
+    out.write(handle)?;
+    Ok(())
+}
+
+pub fn object_signal(handle: HandleValue, clear: u32, set: u32) -> RcResult<()> {
+    let object = current_process()
+        .read()
+        .handles
+        .get_dyn_object(handle, Rights::SIGNAL)?;
+
+    let clear = Signals::from_bits_retain(clear);
+    let set = Signals::from_bits_retain(set);
+    if !object.allowed_user_signals().contains(clear | set) {
+        return Err(RcError::INVALID_ARGS);
+    }
+
+    object.base().signal_change(clear, set);
+    Ok(())
+}
+
+pub fn object_wait_one(
+    handle: HandleValue,
+    signals: u32,
+    deadline: u64,
+    mut observed: UserOutPtr<u32>,
+) -> RcResult<()> {
+    let base = current_process()
+        .read()
+        .handles
+        .get_dyn_object(handle, Rights::WAIT)?
+        .base();
+    let signals = Signals::from_bits_retain(signals);
+
+    let result = wait_until(&[base.wait_queue()], deadline_from(deadline), || {
+        base.signals().intersects(signals).then_some(())
+    });
+
+    observed.write_if_not_null(base.signals().bits())?;
+    result
+}
+
+pub fn object_wait_many(
+    mut items: UserInOutPtr<WaitItem>,
+    count: usize,
+    deadline: u64,
+) -> RcResult<()> {
+    if count > MAX_WAIT_ITEMS {
+        return Err(RcError::OUT_OF_RANGE);
+    }
+
+    let mut wait_items = items.read_array(count)?;
+    let bases = {
+        let process = current_process();
+        let process = process.read();
+        wait_items
+            .iter()
+            .map(|item| {
+                Ok(process
+                    .handles
+                    .get_dyn_object(item.handle, Rights::WAIT)?
+                    .base())
+            })
+            .collect::<RcResult<Vec<KObjectBase>>>()?
+    };
+    let queues = bases
+        .iter()
+        .map(|base| base.wait_queue())
+        .collect::<Vec<_>>();
+
+    let result = wait_until(&queues, deadline_from(deadline), || {
+        bases
+            .iter()
+            .zip(wait_items.iter())
+            .any(|(base, item)| base.signals().bits() & item.wait_for != 0)
+            .then_some(())
+    });
+
+    for (base, item) in bases.iter().zip(wait_items.iter_mut()) {
+        item.pending = base.signals().bits();
+    }
+    items.write_array(&wait_items)?;
+    result
+}
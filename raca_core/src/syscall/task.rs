@@ -0,0 +1,159 @@
+use alloc::sync::Arc;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    arch::user::{UserInPtr, UserOutPtr},
+    device::hpet::HPET,
+    error::{RcError, RcResult},
+    memory::SlabBox,
+    module::get_boot_file,
+    object::{Handle, HandleValue, KernelObject, Rights, Signals},
+    task::{
+        context::Context, current_process, current_thread, sleep_until, wait_until, yield_now,
+        CpuMask, ExitReason, Priority, PriorityClass, Process, Thread, SCHEDULER,
+    },
+};
+
+use super::object::deadline_from;
+use super::SyscallFrame;
+
+pub fn process_create(
+    path: UserInPtr<u8>,
+    path_len: usize,
+    mut out: UserOutPtr<HandleValue>,
+) -> RcResult<()> {
+    let path = path.read_string(path_len)?;
+    let data = get_boot_file(&path).ok_or(RcError::NOT_FOUND)?;
+    let process = Process::new_user_process(&path, data)?;
+
+    let handle = Handle::new(process, Rights::DEFAULT_PROCESS);
+    let handle = current_process().write().handles.add(handle);
+    out.write(handle)?;
+    Ok(())
+}
+
+pub fn thread_create(
+    process: HandleValue,
+    entry_point: usize,
+    argument: usize,
+    mut out: UserOutPtr<HandleValue>,
+) -> RcResult<()> {
+    let current = current_process();
+    let process = current
+        .read()
+        .handles
+        .get_object::<RwLock<SlabBox<Process>>>(process, Rights::MANAGE_THREAD)?;
+    let thread = Thread::new_user_thread(Arc::downgrade(&process), entry_point, argument)?;
+
+    let handle = Handle::new(thread, Rights::DEFAULT_THREAD);
+    let handle = current.write().handles.add(handle);
+    out.write(handle)?;
+    Ok(())
+}
+
+/// What the child sees returned from `PROCESS_FORK`. The parent gets `OK`
+/// and a handle to the child.
+const FORK_CHILD: usize = 1;
+
+pub fn process_fork(mut out: UserOutPtr<HandleValue>) -> RcResult<()> {
+    let stack_end = current_thread().read().kernel_stack_end();
+    let mut context = Context::default();
+    context.init_syscall_return(&SyscallFrame::from_stack_end(stack_end), FORK_CHILD);
+
+    let current = current_process();
+    let child = Process::fork(&current, context)?;
+
+    let handle = Handle::new(child, Rights::DEFAULT_PROCESS);
+    let handle = current.write().handles.add(handle);
+    out.write(handle)?;
+    Ok(())
+}
+
+/// Changes the priority of a thread, from `Priority::IDLE` up to
+/// `Priority::HIGHEST`. Real-time priorities starve every other thread and
+/// are kept for the kernel. It takes effect the next time the thread is
+/// switched out or woken.
+pub fn thread_set_priority(thread: HandleValue, priority: usize) -> RcResult<()> {
+    let priority = Priority::new(priority).ok_or(RcError::INVALID_ARGS)?;
+    if priority.class() == PriorityClass::RealTime {
+        return Err(RcError::ACCESS_DENIED);
+    }
+    let thread = current_process()
+        .read()
+        .handles
+        .get_object::<RwLock<SlabBox<Thread>>>(thread, Rights::MANAGE_THREAD)?;
+    thread.write().priority = priority;
+    Ok(())
+}
+
+/// Restricts a thread to the CPUs whose LAPIC id has its bit set in
+/// `mask`, all of them if every bit is set.
+pub fn thread_set_affinity(thread: HandleValue, mask: u64) -> RcResult<()> {
+    let affinity = CpuMask(mask);
+    if !SCHEDULER.is_valid_affinity(affinity) {
+        return Err(RcError::INVALID_ARGS);
+    }
+    let thread = current_process()
+        .read()
+        .handles
+        .get_object::<RwLock<SlabBox<Thread>>>(thread, Rights::MANAGE_THREAD)?;
+    interrupts::without_interrupts(|| SCHEDULER.set_affinity(&thread, affinity));
+    Ok(())
+}
+
+pub fn thread_yield() -> RcResult<()> {
+    yield_now();
+    Ok(())
+}
+
+pub fn thread_sleep(duration: u64) -> RcResult<()> {
+    sleep_until(HPET.elapsed_ns().saturating_add(duration))
+}
+
+/// Sleeps until `deadline`, on the same clock as the deadlines of waits.
+pub fn thread_sleep_until(deadline: u64) -> RcResult<()> {
+    sleep_until(deadline)
+}
+
+pub fn clock_get(mut out: UserOutPtr<u64>) -> RcResult<()> {
+    out.write(HPET.elapsed_ns())?;
+    Ok(())
+}
+
+pub fn thread_exit() -> ! {
+    Thread::exit_current()
+}
+
+/// Exits the whole process, the other threads are killed.
+pub fn process_exit(exit_code: i64) -> ! {
+    current_process()
+        .write()
+        .kill(ExitReason::Exited(exit_code));
+    Thread::exit_current()
+}
+
+pub fn process_kill(process: HandleValue) -> RcResult<()> {
+    let process = current_process()
+        .read()
+        .handles
+        .get_object::<RwLock<SlabBox<Process>>>(process, Rights::DESTROY)?;
+    process.write().kill(ExitReason::Killed);
+    Ok(())
+}
+
+pub fn process_join(process: HandleValue, deadline: u64, mut out: UserOutPtr<i64>) -> RcResult<()> {
+    let process = current_process()
+        .read()
+        .handles
+        .get_object::<RwLock<SlabBox<Process>>>(process, Rights::WAIT)?;
+    let base = process.base();
+
+    wait_until(&[base.wait_queue()], deadline_from(deadline), || {
+        base.signals().contains(Signals::TERMINATED).then_some(())
+    })?;
+
+    let exit_code = process.read().exit_reason.unwrap().exit_code();
+    out.write(exit_code)?;
+    Ok(())
+}
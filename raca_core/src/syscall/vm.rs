@@ -0,0 +1,109 @@
+use x86_64::VirtAddr;
+
+use crate::{
+    arch::user::UserOutPtr,
+    error::{RcError, RcResult},
+    memory::{memory_stats, MemoryStats, MmuFlags, TlbBatch, VmaKind, USER_END},
+    object::{Handle, HandleValue, Rights, Vmo, INVALID_HANDLE},
+    task::current_process,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Checks that a range given to `VM_UNMAP`/`VM_PROTECT` is page-aligned and
+/// lies in user space.
+fn user_range(address: usize, size: usize) -> RcResult<(VirtAddr, VirtAddr)> {
+    let end = address.checked_add(size).ok_or(RcError::INVALID_ARGS)?;
+    if size == 0 || address % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 || end as u64 > USER_END {
+        return Err(RcError::INVALID_ARGS);
+    }
+    Ok((VirtAddr::new(address as u64), VirtAddr::new(end as u64)))
+}
+
+pub fn mem_info(mut out: UserOutPtr<MemoryStats>) -> RcResult<()> {
+    out.write(memory_stats())?;
+    Ok(())
+}
+
+pub fn vmo_create(size: usize, options: u32, mut out: UserOutPtr<HandleValue>) -> RcResult<()> {
+    if options != 0 {
+        return Err(RcError::INVALID_ARGS);
+    }
+
+    let handle = Handle::new(Vmo::new(size)?, Rights::DEFAULT_VMO);
+    let handle = current_process().write().handles.add(handle);
+    out.write(handle)?;
+    Ok(())
+}
+
+/// Maps `size` bytes of the VMO starting at `offset`, or fresh anonymous
+/// memory when `vmo` is `INVALID_HANDLE`. An `address` of 0 lets the kernel
+/// choose where.
+pub fn vm_map(
+    vmo: HandleValue,
+    offset: usize,
+    address: usize,
+    size: usize,
+    permissions: u32,
+    mut out: UserOutPtr<usize>,
+) -> RcResult<()> {
+    let permissions = MmuFlags::from_bits(permissions).ok_or(RcError::INVALID_ARGS)?;
+    let flags = permissions.page_table_flags()?;
+    let address = match address {
+        0 => None,
+        address => Some(VirtAddr::try_new(address as u64).map_err(|_| RcError::INVALID_ARGS)?),
+    };
+
+    let process = current_process();
+    let (kind, max_permissions) = match vmo {
+        INVALID_HANDLE => (VmaKind::Anonymous, MmuFlags::all()),
+        vmo => {
+            let handle = process.read().handles.get_handle(vmo)?;
+            let max_permissions = max_permissions(handle.rights);
+            if !handle.rights.contains(Rights::MAP) || !max_permissions.contains(permissions) {
+                return Err(RcError::ACCESS_DENIED);
+            }
+
+            let vmo = handle.object.downcast::<Vmo>()?;
+            let end = offset.checked_add(size).ok_or(RcError::OUT_OF_RANGE)?;
+            if offset % PAGE_SIZE != 0 || end > vmo.size() {
+                return Err(RcError::OUT_OF_RANGE);
+            }
+            (VmaKind::Vmo { vmo, offset }, max_permissions)
+        }
+    };
+
+    let address = process
+        .write()
+        .vmas
+        .map(address, size as u64, flags, max_permissions, kind)?;
+    out.write(address.as_u64() as usize)?;
+    Ok(())
+}
+
+pub fn vm_unmap(address: usize, size: usize) -> RcResult<()> {
+    let (start, end) = user_range(address, size)?;
+    let mut batch = TlbBatch::new();
+    current_process().write().unmap(start, end, &mut batch);
+    batch.flush();
+    Ok(())
+}
+
+pub fn vm_protect(address: usize, size: usize, permissions: u32) -> RcResult<()> {
+    let permissions = MmuFlags::from_bits(permissions).ok_or(RcError::INVALID_ARGS)?;
+    let (start, end) = user_range(address, size)?;
+    let mut batch = TlbBatch::new();
+    let result = current_process()
+        .write()
+        .protect(start, end, permissions, &mut batch);
+    batch.flush();
+    result
+}
+
+fn max_permissions(rights: Rights) -> MmuFlags {
+    let mut permissions = MmuFlags::empty();
+    permissions.set(MmuFlags::READ, rights.contains(Rights::READ));
+    permissions.set(MmuFlags::WRITE, rights.contains(Rights::WRITE));
+    permissions.set(MmuFlags::EXECUTE, rights.contains(Rights::EXECUTE));
+    permissions
+}
@@ -1,6 +1,10 @@
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::arch::gdt::Selectors;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::SyscallFrame;
+
 #[derive(Debug, Clone, Copy, Default)]
 #[repr(packed)]
 #[allow(dead_code)]
@@ -50,6 +54,51 @@ impl Context {
         self.ss = data_selector.0 as usize;
     }
 
+    /// Makes the context return to user mode from the syscall that saved
+    /// `frame`, with `return_value` as its result.
+    pub fn init_syscall_return(&mut self, frame: &SyscallFrame, return_value: usize) {
+        self.init(
+            frame.rip,
+            VirtAddr::new_truncate(frame.rsp as u64),
+            PhysAddr::zero(),
+            Selectors::get_user_segments(),
+        );
+        self.rflags = frame.rflags;
+        self.r15 = frame.r15;
+        self.r14 = frame.r14;
+        self.r13 = frame.r13;
+        self.r12 = frame.r12;
+        self.rbx = frame.rbx;
+        self.rbp = frame.rbp;
+        self.rax = return_value;
+    }
+
+    #[inline]
+    pub fn set_page_table_address(&mut self, page_table_address: PhysAddr) {
+        self.cr3 = page_table_address.as_u64() as usize;
+    }
+
+    /// Sets the raw value `pop_context!` loads into CR3, PCID included.
+    #[inline]
+    pub fn set_cr3(&mut self, cr3: u64) {
+        self.cr3 = cr3 as usize;
+    }
+
+    #[inline]
+    pub fn set_argument(&mut self, argument: usize) {
+        self.rdi = argument;
+    }
+
+    #[inline]
+    pub fn page_table_address(&self) -> PhysAddr {
+        PhysAddr::new_truncate(self.cr3 as u64).align_down(PAGE_SIZE)
+    }
+
+    #[inline]
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+
     #[inline]
     pub fn address(&self) -> VirtAddr {
         VirtAddr::new(self as *const Context as u64)
@@ -94,7 +143,12 @@ macro_rules! pop_context {
         concat!(
             r#"
             pop r15
+            mov r14, cr3
+            xor r14, r15
+            shl r14, 1
+            jz 2f
             mov cr3, r15
+            2:
             pop r15
             pop r14
             pop r13
@@ -114,3 +168,29 @@ macro_rules! pop_context {
         )
     };
 }
+
+/// Switches to the context whose address the scheduler returned in `rax`,
+/// then lets other CPUs run the thread switched away from, see
+/// `CpuInfo::set_switched_out`.
+#[macro_export]
+macro_rules! switch_context {
+    () => {
+        concat!(
+            r#"
+            mov rsp, rax
+            swapgs
+            mov rax, qword ptr gs:[16]
+            test rax, rax
+            jz 3f
+            mov byte ptr [rax], 0
+            3:
+            swapgs
+            "#,
+            $crate::pop_context!(),
+            r#"
+            sti
+            iretq
+            "#,
+        )
+    };
+}
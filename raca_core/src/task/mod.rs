@@ -1,7 +1,10 @@
 pub mod context;
+pub mod policy;
 pub mod process;
+pub mod reaper;
 pub mod scheduler;
 pub mod stack;
 pub mod thread;
+pub mod wait;
 
-pub use {process::*, scheduler::*, thread::*};
+pub use {policy::*, process::*, scheduler::*, thread::*, wait::*};
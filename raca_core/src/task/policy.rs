@@ -0,0 +1,330 @@
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    sync::Arc,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{CpuMask, Thread, WeakSharedThread};
+
+/// How urgently a thread wants to run. Higher is more urgent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Priority(u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityClass {
+    /// Runs only when nothing else is ready.
+    Idle,
+    Normal,
+    /// Runs before any other class, e.g. drivers with deadlines to meet.
+    RealTime,
+}
+
+impl Priority {
+    pub const IDLE: Self = Self(0);
+    pub const LOWEST: Self = Self(1);
+    pub const DEFAULT: Self = Self(16);
+    pub const HIGHEST: Self = Self(31);
+    pub const REALTIME: Self = Self(32);
+    pub const MAX: Self = Self(39);
+
+    const COUNT: usize = Self::MAX.0 as usize + 1;
+
+    pub fn new(value: usize) -> Option<Self> {
+        (value <= Self::MAX.0 as usize).then_some(Self(value as u8))
+    }
+
+    pub fn class(self) -> PriorityClass {
+        match self {
+            Self::IDLE => PriorityClass::Idle,
+            _ if self >= Self::REALTIME => PriorityClass::RealTime,
+            _ => PriorityClass::Normal,
+        }
+    }
+
+    /// CPU share under fair scheduling, 1024 at the default priority and
+    /// a quarter more for every level above it.
+    pub fn weight(self) -> u64 {
+        let mut weight = DEFAULT_WEIGHT;
+        for _ in self.0..Self::DEFAULT.0 {
+            weight = weight * 4 / 5;
+        }
+        for _ in Self::DEFAULT.0..self.0.min(Self::HIGHEST.0) {
+            weight = weight * 5 / 4;
+        }
+        weight.max(1)
+    }
+}
+
+const DEFAULT_WEIGHT: u64 = 1024;
+
+/// A thread waiting in a run queue, along with what policies decide by.
+pub struct Runnable {
+    pub thread: WeakSharedThread,
+    pub priority: Priority,
+    /// Nanoseconds run, scaled by the inverse of the priority weight.
+    pub virtual_runtime: u64,
+    pub affinity: CpuMask,
+    on_cpu: Arc<AtomicBool>,
+}
+
+impl Runnable {
+    pub fn new(thread: WeakSharedThread, state: &Thread) -> Self {
+        Self {
+            thread,
+            priority: state.priority,
+            virtual_runtime: state.virtual_runtime,
+            affinity: state.affinity,
+            on_cpu: state.on_cpu.clone(),
+        }
+    }
+
+    /// Whether a CPU still runs on the kernel stack of the thread, which
+    /// must not be switched to until it left.
+    pub fn is_on_cpu(&self) -> bool {
+        self.on_cpu.load(Ordering::Acquire)
+    }
+
+    pub fn set_on_cpu(&self) {
+        self.on_cpu.store(true, Ordering::Relaxed);
+    }
+
+    /// Accounts `elapsed` nanoseconds of running to the thread.
+    pub fn charge(thread: &mut Thread, elapsed: u64) {
+        let elapsed = elapsed * DEFAULT_WEIGHT / thread.priority.weight();
+        thread.virtual_runtime = thread.virtual_runtime.saturating_add(elapsed);
+    }
+}
+
+/// Decides which ready thread of a CPU runs next. Each CPU has a policy of
+/// its own, the scheduler takes care of locking and moving threads around.
+pub trait SchedulingPolicy: Send {
+    /// Queues a thread that is ready to run.
+    fn enqueue(&mut self, runnable: Runnable);
+
+    /// Takes the thread that should run next off the queue.
+    fn pick_next(&mut self) -> Option<Runnable>;
+
+    /// Whether the running thread should give way to a queued one. Asked
+    /// on every timer tick.
+    fn should_preempt(&self, current: &Runnable) -> bool;
+
+    fn len(&self) -> usize;
+
+    /// Takes the first thread `accept` agrees to, in the order they would
+    /// run, e.g. one allowed to move to another CPU.
+    fn take(&mut self, accept: &mut dyn FnMut(&Runnable) -> bool) -> Option<Runnable>;
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&WeakSharedThread) -> bool);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    RoundRobin,
+    Priority,
+    Fair,
+}
+
+impl PolicyKind {
+    pub fn create(self) -> Box<dyn SchedulingPolicy> {
+        match self {
+            Self::RoundRobin => Box::new(RoundRobin::default()),
+            Self::Priority => Box::new(PriorityPolicy::new()),
+            Self::Fair => Box::new(FairPolicy::new()),
+        }
+    }
+}
+
+fn take_from(
+    queue: &mut VecDeque<Runnable>,
+    accept: &mut dyn FnMut(&Runnable) -> bool,
+) -> Option<Runnable> {
+    let index = queue.iter().position(|runnable| accept(runnable))?;
+    queue.remove(index)
+}
+
+/// Every thread gets a tick in turn, whatever its priority.
+#[derive(Default)]
+pub struct RoundRobin {
+    ready: VecDeque<Runnable>,
+}
+
+impl SchedulingPolicy for RoundRobin {
+    fn enqueue(&mut self, runnable: Runnable) {
+        self.ready.push_back(runnable);
+    }
+
+    fn pick_next(&mut self) -> Option<Runnable> {
+        self.ready.pop_front()
+    }
+
+    fn should_preempt(&self, _current: &Runnable) -> bool {
+        !self.ready.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len()
+    }
+
+    fn take(&mut self, accept: &mut dyn FnMut(&Runnable) -> bool) -> Option<Runnable> {
+        take_from(&mut self.ready, accept)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&WeakSharedThread) -> bool) {
+        self.ready.retain(|runnable| keep(&runnable.thread));
+    }
+}
+
+/// The most urgent ready threads take turns, lower priorities wait until
+/// they are all blocked.
+pub struct PriorityPolicy {
+    levels: [VecDeque<Runnable>; Priority::COUNT],
+    len: usize,
+}
+
+impl PriorityPolicy {
+    pub fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; Priority::COUNT],
+            len: 0,
+        }
+    }
+
+    fn highest(&self) -> Option<Priority> {
+        let level = self.levels.iter().rposition(|level| !level.is_empty())?;
+        Some(Priority(level as u8))
+    }
+}
+
+impl SchedulingPolicy for PriorityPolicy {
+    fn enqueue(&mut self, runnable: Runnable) {
+        self.levels[runnable.priority.0 as usize].push_back(runnable);
+        self.len += 1;
+    }
+
+    fn pick_next(&mut self) -> Option<Runnable> {
+        let highest = self.highest()?;
+        self.len -= 1;
+        self.levels[highest.0 as usize].pop_front()
+    }
+
+    fn should_preempt(&self, current: &Runnable) -> bool {
+        self.highest()
+            .is_some_and(|highest| highest >= current.priority)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn take(&mut self, accept: &mut dyn FnMut(&Runnable) -> bool) -> Option<Runnable> {
+        let runnable = self
+            .levels
+            .iter_mut()
+            .rev()
+            .find_map(|level| take_from(level, accept))?;
+        self.len -= 1;
+        Some(runnable)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&WeakSharedThread) -> bool) {
+        for level in self.levels.iter_mut() {
+            level.retain(|runnable| keep(&runnable.thread));
+        }
+        self.len = self.levels.iter().map(VecDeque::len).sum();
+    }
+}
+
+/// A running thread keeps the CPU at least this long before a fairer
+/// choice takes over.
+const MIN_GRANULARITY_NS: u64 = 4_000_000;
+
+/// Shares the CPU between normal threads by priority weight, running the
+/// one that got the least so far, like Linux's CFS. Real-time threads go
+/// first by priority, idle ones last.
+pub struct FairPolicy {
+    real_time: PriorityPolicy,
+    /// Keyed by virtual runtime, then arrival to keep equal keys apart.
+    normal: BTreeMap<(u64, u64), Runnable>,
+    idle: VecDeque<Runnable>,
+    /// Never decreases, so that threads back from a long sleep do not
+    /// monopolize the CPU to catch up.
+    min_virtual_runtime: u64,
+    next_arrival: u64,
+}
+
+impl FairPolicy {
+    pub fn new() -> Self {
+        Self {
+            real_time: PriorityPolicy::new(),
+            normal: BTreeMap::new(),
+            idle: VecDeque::new(),
+            min_virtual_runtime: 0,
+            next_arrival: 0,
+        }
+    }
+}
+
+impl SchedulingPolicy for FairPolicy {
+    fn enqueue(&mut self, mut runnable: Runnable) {
+        match runnable.priority.class() {
+            PriorityClass::RealTime => self.real_time.enqueue(runnable),
+            PriorityClass::Idle => self.idle.push_back(runnable),
+            PriorityClass::Normal => {
+                runnable.virtual_runtime = runnable.virtual_runtime.max(self.min_virtual_runtime);
+                let key = (runnable.virtual_runtime, self.next_arrival);
+                self.next_arrival += 1;
+                self.normal.insert(key, runnable);
+            }
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<Runnable> {
+        if let Some(runnable) = self.real_time.pick_next() {
+            return Some(runnable);
+        }
+        if let Some((_, runnable)) = self.normal.pop_first() {
+            self.min_virtual_runtime = self.min_virtual_runtime.max(runnable.virtual_runtime);
+            return Some(runnable);
+        }
+        self.idle.pop_front()
+    }
+
+    fn should_preempt(&self, current: &Runnable) -> bool {
+        match current.priority.class() {
+            PriorityClass::RealTime => self.real_time.should_preempt(current),
+            PriorityClass::Normal => {
+                let fairer = self.normal.first_key_value().is_some_and(|(_, next)| {
+                    next.virtual_runtime + MIN_GRANULARITY_NS < current.virtual_runtime
+                });
+                self.real_time.len() != 0 || fairer
+            }
+            PriorityClass::Idle => self.len() != 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.real_time.len() + self.normal.len() + self.idle.len()
+    }
+
+    fn take(&mut self, accept: &mut dyn FnMut(&Runnable) -> bool) -> Option<Runnable> {
+        if let Some(runnable) = self.real_time.take(accept) {
+            return Some(runnable);
+        }
+        let key = self
+            .normal
+            .iter()
+            .find(|(_, runnable)| accept(runnable))
+            .map(|(&key, _)| key);
+        if let Some(key) = key {
+            return self.normal.remove(&key);
+        }
+        take_from(&mut self.idle, accept)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&WeakSharedThread) -> bool) {
+        self.real_time.retain(keep);
+        self.normal.retain(|_, runnable| keep(&runnable.thread));
+        self.idle.retain(|runnable| keep(&runnable.thread));
+    }
+}
@@ -5,25 +5,33 @@ use alloc::{
     collections::vec_deque::VecDeque,
     string::String,
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
-use object::{File, Object, ObjectSegment};
+use object::elf::{PF_W, PF_X};
+use object::{Architecture, BinaryFormat, File, Object, ObjectSegment, Segment, SegmentFlags};
 use spin::{Lazy, RwLock};
-use x86_64::{instructions::interrupts, structures::paging::OffsetPageTable, VirtAddr};
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::{instructions::interrupts, VirtAddr};
 
-use crate::memory::{
-    ExtendedPageTable, MappingType, MemoryManager, FRAME_ALLOCATOR, KERNEL_PAGE_TABLE,
-};
+use crate::error::{RcError, RcResult};
+use crate::memory::{Access, ExtendedPageTable, MmuFlags, Vma, VmaKind, VmaList};
+use crate::memory::{SlabAllocator, SlabBox, TlbBatch, FRAME_ALLOCATOR, KERNEL_PAGE_TABLE};
+use crate::memory::{PAGE_SIZE, USER_END};
+use crate::object::{Handle, HandleTable, KObjectBase, KernelObject, ObjectType, Rights};
 
+use super::context::Context;
+use super::stack::UserStack;
 use super::thread::{SharedThread, Thread};
+use super::SCHEDULER;
 
 const KERNEL_PROCESS_NAME: &str = "kernel";
 
 static PROCESSES: RwLock<VecDeque<SharedProcess>> = RwLock::new(VecDeque::new());
 pub static KERNEL_PROCESS: Lazy<SharedProcess> = Lazy::new(|| Process::new_kernel_process());
 
-pub(super) type SharedProcess = Arc<RwLock<Box<Process>>>;
-pub(super) type WeakSharedProcess = Weak<RwLock<Box<Process>>>;
+pub type SharedProcess = Arc<RwLock<SlabBox<Process>>>;
+pub type WeakSharedProcess = Weak<RwLock<SlabBox<Process>>>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ProcessId(pub u64);
@@ -35,12 +43,48 @@ impl ProcessId {
     }
 }
 
+/// CPU exceptions that terminate a user process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    PageFault,
+    GeneralProtectionFault,
+    InvalidOpcode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Exited(i64),
+    Killed,
+    Exception(Exception),
+}
+
+impl ExitReason {
+    /// The status reported to joiners. Killed processes and processes
+    /// taken down by an exception get negative codes.
+    pub fn exit_code(&self) -> i64 {
+        match self {
+            Self::Exited(code) => *code,
+            Self::Killed => -1,
+            Self::Exception(exception) => -0x100 - *exception as i64,
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct Process {
     pub id: ProcessId,
     pub name: String,
     pub page_table: OffsetPageTable<'static>,
+    pub vmas: VmaList,
     pub threads: Vec<SharedThread>,
+    pub handles: HandleTable,
+    pub base: KObjectBase,
+    /// Set once the process starts exiting, either by its last thread
+    /// leaving or by being killed.
+    pub exit_reason: Option<ExitReason>,
+    pub user_stack_count: usize,
+    /// Slots below `user_stack_count` whose threads exited, reused first.
+    pub free_user_stacks: Vec<usize>,
 }
 
 impl Process {
@@ -48,29 +92,148 @@ impl Process {
         let process = Process {
             id: ProcessId::new(),
             name: String::from(name),
-            page_table: unsafe { KERNEL_PAGE_TABLE.lock().deep_copy() },
+            page_table: unsafe { KERNEL_PAGE_TABLE.lock().new_address_space() },
+            vmas: VmaList::new(),
             threads: Default::default(),
+            handles: HandleTable::new(),
+            base: KObjectBase::new(),
+            exit_reason: None,
+            user_stack_count: 0,
+            free_user_stacks: Vec::new(),
         };
 
         process
     }
 
     pub fn new_kernel_process() -> SharedProcess {
-        let process = Arc::new(RwLock::new(Box::new(Self::new(KERNEL_PROCESS_NAME))));
+        let process = Arc::new(RwLock::new(Box::new_in(
+            Self::new(KERNEL_PROCESS_NAME),
+            SlabAllocator,
+        )));
         PROCESSES.write().push_back(process.clone());
         process
     }
 
-    pub fn new_user_process(name: &str, elf_data: &'static [u8]) {
-        let binary = ProcessBinary::parse(elf_data);
+    pub fn new_user_process(name: &str, elf_data: &'static [u8]) -> RcResult<SharedProcess> {
+        let binary = ProcessBinary::parse(elf_data)?;
         interrupts::without_interrupts(|| {
-            let process = Arc::new(RwLock::new(Box::new(Self::new(name))));
-            ProcessBinary::map_segments(&binary, &mut process.write().page_table, None);
-            Thread::new_user_thread(Arc::downgrade(&process), binary.entry() as usize);
+            let process = Arc::new(RwLock::new(Box::new_in(Self::new(name), SlabAllocator)));
+            ProcessBinary::map_segments(&binary, &mut process.write().vmas, None)?;
+
+            let self_handle = Handle::new(process.clone(), Rights::DEFAULT_PROCESS);
+            let self_handle = process.write().handles.add(self_handle);
+            Thread::new_user_thread(
+                Arc::downgrade(&process),
+                binary.entry() as usize,
+                self_handle as usize,
+            )?;
             PROCESSES.write().push_back(process.clone());
+            Ok(process)
+        })
+    }
+
+    /// Creates a copy of the process whose private memory is shared
+    /// copy-on-write. Its only thread resumes from `context`.
+    pub fn fork(parent: &SharedProcess, context: Context) -> RcResult<SharedProcess> {
+        interrupts::without_interrupts(|| {
+            // Declared first so that it is flushed after the locks are gone.
+            let mut batch = TlbBatch::new();
+            let name = parent.read().name.clone();
+            let child = Arc::new(RwLock::new(Box::new_in(Self::new(&name), SlabAllocator)));
+            {
+                let mut parent_guard = parent.write();
+                let parent_process = &mut **parent_guard;
+                if parent_process.is_exiting() {
+                    return Err(RcError::BAD_STATE);
+                }
+
+                let mut child_guard = child.write();
+                let child_process = &mut **child_guard;
+                child_process.vmas = parent_process.vmas.fork(
+                    &mut parent_process.page_table,
+                    &mut child_process.page_table,
+                    &mut batch,
+                )?;
+                child_process.user_stack_count = parent_process.user_stack_count;
+                child_process.free_user_stacks = parent_process.free_user_stacks.clone();
+                child_process.handles = parent_process.handles.fork(parent, &child);
+            }
+
+            Thread::new_forked_thread(Arc::downgrade(&child), context)?;
+            PROCESSES.write().push_back(child.clone());
+            Ok(child)
+        })
+    }
+
+    #[inline]
+    pub fn is_exiting(&self) -> bool {
+        self.exit_reason.is_some()
+    }
+
+    /// Makes every thread of the process exit as soon as possible.
+    pub fn kill(&mut self, reason: ExitReason) {
+        self.exit_reason.get_or_insert(reason);
+        interrupts::without_interrupts(|| {
+            for thread in self.threads.iter() {
+                thread.write().killed = true;
+                SCHEDULER.wake(thread);
+                SCHEDULER.interrupt(thread);
+            }
         });
     }
 
+    /// Resolves a fault on a user address by mapping the page it hits.
+    /// Mapping changes go into `batch`, to be flushed after unlocking the
+    /// process, as do those of `unmap` and `protect`.
+    #[inline]
+    pub fn handle_page_fault(
+        &mut self,
+        address: VirtAddr,
+        access: Access,
+        batch: &mut TlbBatch,
+    ) -> RcResult<()> {
+        self.vmas
+            .handle_page_fault(&mut self.page_table, address, access, batch)
+    }
+
+    #[inline]
+    pub fn unmap(&mut self, start: VirtAddr, end: VirtAddr, batch: &mut TlbBatch) {
+        self.vmas.unmap(&mut self.page_table, start, end, batch);
+    }
+
+    #[inline]
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        permissions: MmuFlags,
+        batch: &mut TlbBatch,
+    ) -> RcResult<()> {
+        self.vmas
+            .protect(&mut self.page_table, start, end, permissions, batch)
+    }
+
+    /// Picks the slot for the user stack of a new thread.
+    pub fn allocate_user_stack(&mut self) -> usize {
+        self.free_user_stacks.pop().unwrap_or_else(|| {
+            self.user_stack_count += 1;
+            self.user_stack_count - 1
+        })
+    }
+
+    /// Unmaps the stack of an exited thread and gives its slot back.
+    pub fn free_user_stack(&mut self, stack: UserStack, batch: &mut TlbBatch) {
+        self.unmap(stack.limit(), stack.end_address, batch);
+        self.free_user_stacks.push(stack.index);
+    }
+
+    /// Called when the last thread has exited. The handle table is handed
+    /// back so that it can be dropped without holding the process lock.
+    pub fn terminate(&mut self) -> HandleTable {
+        self.exit_reason.get_or_insert(ExitReason::Exited(0));
+        core::mem::replace(&mut self.handles, HandleTable::new())
+    }
+
     pub fn exit_process(&self) {
         let mut processes = PROCESSES.write();
         if let Some(index) = processes
@@ -82,33 +245,117 @@ impl Process {
     }
 }
 
+impl KernelObject for RwLock<SlabBox<Process>> {
+    fn base(&self) -> KObjectBase {
+        self.read().base.clone()
+    }
+
+    fn object_type(&self) -> ObjectType {
+        ObjectType::Process
+    }
+}
+
 pub struct ProcessBinary;
 
 impl ProcessBinary {
-    fn parse(bin: &'static [u8]) -> File<'static> {
-        File::parse(bin).expect("Failed to parse ELF binary")
+    fn parse(bin: &'static [u8]) -> RcResult<File<'static>> {
+        let file = File::parse(bin).map_err(|_| RcError::INVALID_ARGS)?;
+        if file.format() != BinaryFormat::Elf || file.architecture() != Architecture::X86_64 {
+            return Err(RcError::NOT_SUPPORTED);
+        }
+        Ok(file)
+    }
+
+    /// Returns the range, file data and permissions of a segment.
+    fn parse_segment(
+        segment: &Segment<'static, '_>,
+        base: u64,
+    ) -> RcResult<(VirtAddr, VirtAddr, &'static [u8], MmuFlags)> {
+        log::info!("Mapping {:?}", segment);
+
+        let start = segment.address().checked_add(base);
+        let end = start.and_then(|start| start.checked_add(segment.size()));
+        let (start, end) = start.zip(end).ok_or(RcError::INVALID_ARGS)?;
+        if end > USER_END {
+            return Err(RcError::INVALID_ARGS);
+        }
+
+        let data = segment.data().map_err(|_| RcError::INVALID_ARGS)?;
+        if data.len() as u64 > segment.size() {
+            return Err(RcError::INVALID_ARGS);
+        }
+
+        let SegmentFlags::Elf { p_flags } = segment.flags() else {
+            return Err(RcError::INVALID_ARGS);
+        };
+        let mut permissions = MmuFlags::READ;
+        permissions.set(MmuFlags::WRITE, p_flags & PF_W != 0);
+        permissions.set(MmuFlags::EXECUTE, p_flags & PF_X != 0);
+
+        Ok((VirtAddr::new(start), VirtAddr::new(end), data, permissions))
     }
 
+    /// Registers the `PT_LOAD` segments as regions that get filled on first
+    /// touch, with the permissions of their segment, which `VM_PROTECT` may
+    /// only narrow. A page shared by two segments gets the permissions of
+    /// both, which must not make it both writable and executable.
     pub fn map_segments(
-        elf_file: &File,
-        page_table: &mut OffsetPageTable<'static>,
+        elf_file: &File<'static>,
+        vmas: &mut VmaList,
         base: Option<u64>,
-    ) {
+    ) -> RcResult<()> {
         let base = base.unwrap_or(0);
-        for segment in elf_file.segments() {
-            log::info!("Mapping {:?}", segment);
-            let _ = MemoryManager::alloc_range(
-                VirtAddr::new(segment.address() as u64 + base),
-                segment.size(),
-                MappingType::UserCode.flags(),
-                page_table,
-            );
-            //.expect("Failed to allocate memory for ELF segment");
-
-            if let Ok(data) = segment.data() {
-                page_table.write_to_mapped_address(data, VirtAddr::new(segment.address() + base));
+        let mut segments = elf_file
+            .segments()
+            .map(|segment| Self::parse_segment(&segment, base))
+            .collect::<RcResult<Vec<_>>>()?;
+        segments.retain(|(start, end, ..)| start < end);
+        segments.sort_by_key(|(start, ..)| *start);
+
+        let mut regions: Vec<(Vma, MmuFlags)> = Vec::new();
+        let mut image_end = VirtAddr::zero();
+        for (start, end, data, permissions) in segments {
+            if start < image_end {
+                return Err(RcError::INVALID_ARGS);
+            }
+            image_end = end;
+
+            let mut region_start = start.align_down(PAGE_SIZE);
+            if regions
+                .last()
+                .is_some_and(|(last, _)| region_start < last.end)
+            {
+                // Give the page shared with the previous segment a region
+                // of its own, holding the data of both.
+                let (last, last_permissions) = regions.last_mut().unwrap();
+                let shared_start = last.end - PAGE_SIZE;
+                if last.start < shared_start {
+                    let shared = last.split_off(shared_start);
+                    let shared_permissions = *last_permissions;
+                    regions.push((shared, shared_permissions));
+                }
+
+                let (shared, shared_permissions) = regions.last_mut().unwrap();
+                *shared_permissions |= permissions;
+                shared.flags = shared_permissions.page_table_flags()?;
+                if let VmaKind::Image(images) = &mut shared.kind {
+                    images.push((start, data));
+                }
+                region_start = shared.end;
+            }
+
+            let region_end = end.align_up(PAGE_SIZE);
+            if region_start < region_end {
+                let flags = permissions.page_table_flags()?;
+                let image = VmaKind::Image(vec![(start, data)]);
+                let region = Vma::new(region_start, region_end, flags, image);
+                regions.push((region, permissions));
             }
         }
+
+        regions.into_iter().try_for_each(|(region, permissions)| {
+            vmas.insert(region.with_max_permissions(permissions))
+        })
     }
 }
 
@@ -0,0 +1,43 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{wait_until, CpuMask, Priority, SharedThread, Thread, WaitQueue};
+
+/// Exited threads no CPU runs on anymore, waiting to be freed.
+static EXITED: Mutex<Vec<SharedThread>> = Mutex::new(Vec::new());
+static EXITED_QUEUE: WaitQueue = WaitQueue::new();
+
+pub fn init() {
+    Thread::new_kernel_thread(reap, Priority::DEFAULT, CpuMask::ALL);
+}
+
+/// Hands `threads` over to the reaper. Called by the scheduler with
+/// interrupts disabled, without holding a run queue.
+pub(super) fn hand_over(threads: Vec<SharedThread>) {
+    if threads.is_empty() {
+        return;
+    }
+    EXITED.lock().extend(threads);
+    EXITED_QUEUE.wake_all();
+}
+
+/// Frees the kernel stacks of exited threads, and the threads themselves
+/// unless handles keep them around. Unmapping a stack waits for the other
+/// CPUs to flush their TLBs, which is why it happens here and not in the
+/// scheduler.
+fn reap() {
+    loop {
+        let threads = wait_until(&[&EXITED_QUEUE], None, || {
+            let threads = core::mem::take(&mut *EXITED.lock());
+            (!threads.is_empty()).then_some(threads)
+        })
+        .expect("Reaper stopped waiting");
+
+        for thread in threads {
+            let kernel_stack =
+                interrupts::without_interrupts(|| thread.write().kernel_stack.take());
+            drop(kernel_stack);
+        }
+    }
+}
@@ -1,86 +1,582 @@
+use core::arch::asm;
+use core::cmp;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::{
-    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    boxed::Box,
+    collections::{btree_map::BTreeMap, BinaryHeap},
     sync::Arc,
+    vec::Vec,
 };
 use context::Context;
 use spin::{Lazy, Mutex};
+use x86_64::instructions::interrupts;
 use x86_64::VirtAddr;
 
-use crate::arch::{apic::LAPIC, smp::CPUS};
+use crate::arch::apic::{self, TIME_SLICE_NS};
+use crate::arch::interrupts::InterruptIndex;
+use crate::arch::smp::{current_cpu, current_cpu_id, CPUS};
+use crate::device::hpet::HPET;
+use crate::memory;
 
 use super::*;
 
 pub static SCHEDULER_INIT: AtomicBool = AtomicBool::new(false);
-pub static SCHEDULER: Lazy<Mutex<Scheduler>> = Lazy::new(|| Mutex::new(Scheduler::new()));
+pub static SCHEDULER: Lazy<Scheduler> = Lazy::new(Scheduler::new);
+
+/// The policy every CPU schedules its threads with.
+const POLICY: PolicyKind = PolicyKind::Fair;
+
+/// The CPUs a thread may run on. Bit `n` stands for the CPU with LAPIC id
+/// `n`, CPUs with higher ids are only in `ALL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(pub u64);
+
+impl CpuMask {
+    pub const ALL: Self = Self(!0);
+
+    /// The mask of one CPU, `None` if its id has no bit.
+    pub fn single(lapic_id: u32) -> Option<Self> {
+        1u64.checked_shl(lapic_id).map(Self)
+    }
+
+    pub fn contains(self, lapic_id: u32) -> bool {
+        self == Self::ALL
+            || 1u64
+                .checked_shl(lapic_id)
+                .is_some_and(|bit| self.0 & bit != 0)
+    }
+}
 
 pub fn init() {
-    x86_64::instructions::interrupts::enable();
+    reaper::init();
+    start_scheduling();
     SCHEDULER_INIT.store(true, Ordering::SeqCst);
     log::info!("Scheduler initialized, interrupts enabled!");
 }
 
+/// Hands this CPU over to the scheduler, which takes it from the next
+/// timer interrupt on.
+pub fn start_scheduling() {
+    apic::set_timer_deadline(Some(HPET.elapsed_ns()));
+    memory::join_shootdowns();
+    interrupts::enable();
+}
+
+/// Lets the other ready threads of this CPU run before the caller goes on.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.local_run_queue().lock().yield_requested = true;
+    });
+    unsafe { asm!("int {}", const InterruptIndex::Yield as u8) };
+}
+
+fn send_reschedule(cpu_id: u32) {
+    apic::send_ipi(InterruptIndex::Reschedule, cpu_id);
+}
+
+pub fn current_thread() -> SharedThread {
+    let thread = SCHEDULER.current_thread();
+    thread.upgrade().unwrap()
+}
+
+pub fn current_process() -> SharedProcess {
+    let process = current_thread().read().process.clone();
+    process.upgrade().unwrap()
+}
+
+/// Wakes up `thread` at `deadline`, unless something woke it before.
+struct Timer {
+    deadline: u64,
+    thread: WeakSharedThread,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    /// Earliest first, as `BinaryHeap` is a max-heap.
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// The threads of one CPU. Only that CPU switches between them, others
+/// lock the queue to hand it woken threads or to steal from it.
+struct RunQueue {
+    current: WeakSharedThread,
+    /// Runs when no other thread can. Never queued.
+    idle: SharedThread,
+    /// When `current` was last charged for its running time, in HPET
+    /// nanoseconds.
+    current_since: u64,
+    ready: Box<dyn SchedulingPolicy>,
+    /// Threads that exited but may still be running on their kernel stack,
+    /// kept alive until the CPU switches away.
+    exited: Vec<SharedThread>,
+    /// Exited threads the CPU switched away from. The switch itself still
+    /// runs on their stack, so they go to the reaper on the next one.
+    finished: Vec<SharedThread>,
+    /// Threads that blocked here with a deadline.
+    timers: BinaryHeap<Timer>,
+    /// When the timer of the CPU fires next, `u64::MAX` if it is off.
+    timer_deadline: u64,
+    /// Set by `yield_now` to switch even if the policy would not.
+    yield_requested: bool,
+    /// The `on_cpu` flag of the thread the CPU last switched away from,
+    /// kept alive until the timer interrupt cleared it.
+    switched_out: Option<Arc<AtomicBool>>,
+}
+
+impl RunQueue {
+    fn new(cpu_id: u32) -> Self {
+        Self {
+            current: Thread::new_boot_thread(cpu_id),
+            idle: Thread::new_idle_thread(cpu_id),
+            current_since: 0,
+            ready: POLICY.create(),
+            exited: Vec::new(),
+            finished: Vec::new(),
+            timers: BinaryHeap::new(),
+            timer_deadline: u64::MAX,
+            yield_requested: false,
+            switched_out: None,
+        }
+    }
+
+    /// Skips threads another CPU is still switching away from.
+    fn pop_ready(&mut self) -> Option<Runnable> {
+        while let Some(runnable) = self.ready.take(&mut |runnable| !runnable.is_on_cpu()) {
+            if runnable.thread.strong_count() > 0 {
+                return Some(runnable);
+            }
+        }
+        None
+    }
+}
+
+/// Spreads threads over one run queue per CPU. CPUs with fewer ready
+/// threads than others steal from the busiest one, and hand threads to
+/// those with none.
+///
+/// There is no periodic tick: each CPU programs its timer for the next
+/// deadline of a thread blocked on it, or for the end of the time slice
+/// when other threads are waiting for it.
+///
+/// Locks are taken in the order process, run queue, thread, and always
+/// with interrupts disabled.
 pub struct Scheduler {
-    current_threads: BTreeMap<u32, WeakSharedThread>,
-    ready_threads: VecDeque<WeakSharedThread>,
+    run_queues: BTreeMap<u32, Mutex<RunQueue>>,
 }
 
 impl Scheduler {
     pub fn new() -> Self {
-        let current_threads = CPUS
+        let run_queues = CPUS
             .read()
             .iter_id()
-            .map(|lapic_id| (*lapic_id, Thread::get_init_thread()))
+            .map(|&lapic_id| (lapic_id, Mutex::new(RunQueue::new(lapic_id))))
             .collect();
 
-        Self {
-            current_threads,
-            ready_threads: VecDeque::new(),
+        Self { run_queues }
+    }
+
+    fn run_queue(&self, cpu_id: u32) -> &Mutex<RunQueue> {
+        &self.run_queues[&cpu_id]
+    }
+
+    fn local_run_queue(&self) -> &Mutex<RunQueue> {
+        self.run_queue(current_cpu_id())
+    }
+
+    /// Whether some CPU is in `affinity`.
+    pub fn is_valid_affinity(&self, affinity: CpuMask) -> bool {
+        self.run_queues
+            .keys()
+            .any(|&cpu_id| affinity.contains(cpu_id))
+    }
+
+    /// Picks the CPU to queue a thread on: `preferred` if allowed, else the
+    /// allowed one with the fewest ready threads.
+    fn place(&self, affinity: CpuMask, preferred: Option<u32>) -> u32 {
+        if let Some(cpu_id) = preferred.filter(|&cpu_id| affinity.contains(cpu_id)) {
+            return cpu_id;
         }
+        self.run_queues
+            .iter()
+            .filter(|(&cpu_id, _)| affinity.contains(cpu_id))
+            .min_by_key(|(_, run_queue)| run_queue.lock().ready.len())
+            .map(|(&cpu_id, _)| cpu_id)
+            .expect("No CPU the thread may run on")
     }
 
-    #[inline]
-    pub fn add(&mut self, thread: WeakSharedThread) {
-        self.ready_threads.push_back(thread);
+    fn enqueue(&self, cpu_id: u32, runnable: Runnable) {
+        let mut run_queue = self.run_queue(cpu_id).lock();
+        run_queue.ready.enqueue(runnable);
+        Self::kick(cpu_id, &mut run_queue);
     }
 
-    #[inline]
-    pub fn remove(&mut self, thread: WeakSharedThread) {
-        self.ready_threads.retain(|other| {
-            let other = other.upgrade().unwrap();
-            !Arc::ptr_eq(&other, &thread.upgrade().unwrap())
-        });
+    /// Queues a new thread on the allowed CPU with the fewest ready threads.
+    pub fn add(&self, thread: WeakSharedThread) {
+        let Some(shared) = thread.upgrade() else {
+            return;
+        };
+        let affinity = shared.read().affinity;
+        let cpu_id = self.place(affinity, None);
+
+        let runnable = {
+            let mut shared = shared.write();
+            shared.cpu = cpu_id;
+            Runnable::new(thread, &shared)
+        };
+        self.enqueue(cpu_id, runnable);
+    }
+
+    /// Restricts `thread` to the CPUs in `affinity`. A queued thread moves
+    /// right away, a running one when it is next switched out.
+    pub fn set_affinity(&self, thread: &SharedThread, affinity: CpuMask) {
+        let (cpu_id, running) = {
+            let mut thread = thread.write();
+            thread.affinity = affinity;
+            (thread.cpu, thread.running)
+        };
+
+        if running {
+            if !affinity.contains(cpu_id) {
+                send_reschedule(cpu_id);
+            }
+            return;
+        }
+
+        // Queued threads may have been stolen by any CPU.
+        let weak = Arc::downgrade(thread);
+        for (&other, run_queue) in self.run_queues.iter() {
+            if affinity.contains(other) {
+                continue;
+            }
+            let taken = run_queue
+                .lock()
+                .ready
+                .take(&mut |runnable| runnable.thread.ptr_eq(&weak));
+            if let Some(mut runnable) = taken {
+                runnable.affinity = affinity;
+                self.enqueue(self.place(affinity, None), runnable);
+                return;
+            }
+        }
+    }
+
+    /// Makes sure the CPU of `run_queue` gets to its ready threads within
+    /// a time slice, if its timer is not due sooner anyway.
+    fn kick(cpu_id: u32, run_queue: &mut RunQueue) {
+        let deadline = HPET.elapsed_ns() + TIME_SLICE_NS;
+        if run_queue.timer_deadline <= deadline {
+            return;
+        }
+        run_queue.timer_deadline = deadline;
+        match cpu_id == current_cpu_id() {
+            true => apic::set_timer_deadline(Some(deadline)),
+            // Its scheduler runs right away and programs the timer.
+            false => send_reschedule(cpu_id),
+        }
+    }
+
+    pub fn remove(&self, thread: WeakSharedThread) {
+        for run_queue in self.run_queues.values() {
+            run_queue
+                .lock()
+                .ready
+                .retain(&mut |other| !other.ptr_eq(&thread));
+        }
     }
 
     #[inline]
     pub fn current_thread(&self) -> WeakSharedThread {
-        let lapic_id = unsafe { LAPIC.lock().id() };
-        self.current_threads[&lapic_id].clone()
+        self.local_run_queue().lock().current.clone()
     }
 
-    pub fn schedule(&mut self, context: VirtAddr) -> VirtAddr {
-        let lapic_id = unsafe { LAPIC.lock().id() };
+    /// Takes the current thread off the ready rotation. It keeps running
+    /// until it yields or the timer switches it out, and is not picked
+    /// again until `wake` is called or `deadline` (in HPET nanoseconds) passes.
+    pub fn block_current(&self, deadline: Option<u64>) {
+        let mut run_queue = self.local_run_queue().lock();
+        let thread = run_queue.current.clone();
+        {
+            let thread = thread.upgrade().unwrap();
+            let mut thread = thread.write();
+            thread.state = ThreadState::Blocked;
+            thread.sleep_deadline = deadline;
+        }
+
+        let Some(deadline) = deadline else {
+            return;
+        };
+        run_queue.timers.push(Timer { deadline, thread });
+        if deadline < run_queue.timer_deadline {
+            run_queue.timer_deadline = deadline;
+            apic::set_timer_deadline(Some(deadline));
+        }
+    }
+
+    /// Takes the current thread out of scheduling for good.
+    pub fn exit_current(&self) {
+        let mut run_queue = self.local_run_queue().lock();
+        let thread = run_queue.current.upgrade().unwrap();
+        thread.write().state = ThreadState::Exited;
+        run_queue.exited.push(thread);
+    }
+
+    pub fn wake(&self, thread: &SharedThread) {
+        let weak = Arc::downgrade(thread);
+        let (cpu_id, runnable) = {
+            let mut thread = thread.write();
+            if thread.state != ThreadState::Blocked {
+                return;
+            }
+            thread.state = ThreadState::Ready;
+            thread.sleep_deadline = None;
+            // A thread still running on its CPU is requeued when it gets
+            // switched out.
+            let runnable = (!thread.running).then(|| Runnable::new(weak, &thread));
+            (thread.cpu, runnable)
+        };
 
-        let last_thread = self.current_threads[&lapic_id]
-            .upgrade()
-            .and_then(|thread| {
-                thread.write().context = Context::from_address(context);
-                Some(self.current_threads[&lapic_id].clone())
+        match runnable {
+            Some(runnable) => {
+                let cpu_id = self.place(runnable.affinity, Some(cpu_id));
+                self.enqueue(cpu_id, runnable);
+            }
+            // It may be halted waiting for this.
+            None if cpu_id != current_cpu_id() => send_reschedule(cpu_id),
+            None => {}
+        }
+    }
+
+    /// Makes the CPU running `thread` enter the scheduler, e.g. so that a
+    /// killed thread busy in user mode exits. Without other threads to run
+    /// there, the timer of that CPU may be off.
+    pub fn interrupt(&self, thread: &SharedThread) {
+        let (cpu_id, running) = {
+            let thread = thread.read();
+            (thread.cpu, thread.running)
+        };
+        if running && cpu_id != current_cpu_id() {
+            send_reschedule(cpu_id);
+        }
+    }
+
+    /// Wakes the threads whose deadline passed. A timer whose thread was
+    /// woken before, and maybe blocked again since, is dropped. Threads no
+    /// longer allowed here go to `migrating`.
+    fn wake_expired(
+        cpu_id: u32,
+        run_queue: &mut RunQueue,
+        now: u64,
+        migrating: &mut Vec<Runnable>,
+    ) {
+        while run_queue
+            .timers
+            .peek()
+            .is_some_and(|timer| timer.deadline <= now)
+        {
+            let timer = run_queue.timers.pop().unwrap();
+            let Some(thread) = timer.thread.upgrade() else {
+                continue;
+            };
+            let mut thread = thread.write();
+            if thread.state != ThreadState::Blocked || thread.sleep_deadline != Some(timer.deadline)
+            {
+                continue;
+            }
+            thread.state = ThreadState::Ready;
+            thread.sleep_deadline = None;
+
+            if !thread.running {
+                let runnable = Runnable::new(timer.thread, &thread);
+                match thread.affinity.contains(cpu_id) {
+                    true => {
+                        thread.cpu = cpu_id;
+                        run_queue.ready.enqueue(runnable);
+                    }
+                    false => migrating.push(runnable),
+                }
+            } else if thread.cpu != cpu_id {
+                send_reschedule(thread.cpu);
+            }
+        }
+    }
+
+    /// Moves a thread from the busiest CPU to this one if it has at least
+    /// two ready threads more, or from this one to a CPU without any if
+    /// this one has a few. Other queues are only try-locked, as their CPUs
+    /// may be balancing towards this one at the same time.
+    fn balance(&self, cpu_id: u32, run_queue: &mut RunQueue) {
+        let loads = || {
+            self.run_queues
+                .iter()
+                .filter(move |(&other, _)| other != cpu_id)
+                .filter_map(|(&other, queue)| Some((other, queue.try_lock()?.ready.len())))
+        };
+        let ready_count = run_queue.ready.len();
+
+        if let Some((busiest, _)) = loads()
+            .max_by_key(|&(_, count)| count)
+            .filter(|&(_, count)| count >= ready_count + 2)
+        {
+            let stolen = self.run_queue(busiest).try_lock().and_then(|mut queue| {
+                queue.ready.take(&mut |runnable| {
+                    runnable.affinity.contains(cpu_id) && !runnable.is_on_cpu()
+                })
             });
+            if let Some(runnable) = stolen {
+                run_queue.ready.enqueue(runnable);
+            }
+        } else if ready_count >= 2 {
+            let Some((idle, _)) = loads().find(|&(_, count)| count == 0) else {
+                return;
+            };
+            let Some(mut idle_queue) = self.run_queue(idle).try_lock() else {
+                return;
+            };
+            let pushed = run_queue
+                .ready
+                .take(&mut |runnable| runnable.affinity.contains(idle) && !runnable.is_on_cpu());
+            if let Some(runnable) = pushed {
+                idle_queue.ready.enqueue(runnable);
+                Self::kick(idle, &mut idle_queue);
+            }
+        }
+    }
+
+    pub fn schedule(&self, context: VirtAddr) -> VirtAddr {
+        let cpu_id = current_cpu_id();
+        let now = HPET.elapsed_ns();
+
+        let mut migrating = Vec::new();
+        let mut run_queue = self.run_queue(cpu_id).lock();
+        let reapable = core::mem::take(&mut run_queue.finished);
+        Self::wake_expired(cpu_id, &mut run_queue, now, &mut migrating);
+        self.balance(cpu_id, &mut run_queue);
 
-        if let Some(next_thread) = self.ready_threads.pop_front() {
-            self.current_threads.insert(lapic_id, next_thread);
-            if let Some(last_thread) = last_thread {
-                self.ready_threads.push_back(last_thread);
+        let elapsed = now.saturating_sub(core::mem::replace(&mut run_queue.current_since, now));
+        let yielded = core::mem::take(&mut run_queue.yield_requested);
+
+        // A thread that cannot go on without anything else to run leaves
+        // the CPU to the idle thread.
+        let idle = run_queue.idle.clone();
+        let last_thread = run_queue.current.clone();
+        let mut last_on_cpu = None;
+        let mut to_idle = false;
+        let next_thread = match last_thread.upgrade() {
+            Some(thread) if Arc::ptr_eq(&thread, &idle) => {
+                let mut thread = thread.write();
+                last_on_cpu = Some(thread.on_cpu.clone());
+                thread.context = Context::from_address(context);
+                let next_thread = run_queue.pop_ready();
+                thread.running = next_thread.is_none();
+                next_thread
+            }
+            Some(thread) => {
+                let mut thread = thread.write();
+                last_on_cpu = Some(thread.on_cpu.clone());
+                thread.context = Context::from_address(context);
+                Runnable::charge(&mut thread, elapsed);
+
+                let last = Runnable::new(last_thread.clone(), &thread);
+                let ready = thread.state == ThreadState::Ready;
+                let allowed = thread.affinity.contains(cpu_id);
+                let switch = !ready || !allowed || yielded || run_queue.ready.should_preempt(&last);
+                let next_thread = match switch {
+                    true => run_queue.pop_ready(),
+                    false => None,
+                };
+                to_idle = next_thread.is_none() && (!ready || !allowed);
+                if next_thread.is_some() || to_idle {
+                    thread.running = false;
+                    match (ready, allowed) {
+                        (true, true) => run_queue.ready.enqueue(last),
+                        (true, false) => migrating.push(last),
+                        (false, _) => {}
+                    }
+                }
+                next_thread
+            }
+            None => {
+                to_idle = true;
+                run_queue.pop_ready()
             }
+        };
+
+        if let Some(next_thread) = next_thread {
+            next_thread.set_on_cpu();
+            if let Some(thread) = next_thread.thread.upgrade() {
+                let mut thread = thread.write();
+                thread.running = true;
+                thread.cpu = cpu_id;
+                thread.virtual_runtime = next_thread.virtual_runtime;
+            }
+            run_queue.current = next_thread.thread;
+        } else if to_idle {
+            let mut thread = idle.write();
+            thread.running = true;
+            thread.on_cpu.store(true, Ordering::Relaxed);
+            drop(thread);
+            run_queue.current = Arc::downgrade(&idle);
         }
 
-        let next_thread = self.current_threads[&lapic_id].upgrade().unwrap();
-        let next_thread = next_thread.read();
+        let current_thread = run_queue.current.clone();
+        // Other CPUs must not switch to the last thread before this one is
+        // done with its stack, which is only after returning from here.
+        run_queue.switched_out = last_on_cpu.filter(|_| !current_thread.ptr_eq(&last_thread));
+        let switched_out = run_queue.switched_out.clone();
+        let (exited, finished) = core::mem::take(&mut run_queue.exited)
+            .into_iter()
+            .partition::<Vec<_>, _>(|thread| current_thread.ptr_eq(&Arc::downgrade(thread)));
+        run_queue.exited = exited;
+        run_queue.finished.extend(finished);
+
+        // Threads left waiting need the CPU back after a time slice.
+        let slice_end = (run_queue.ready.len() != 0).then_some(now + TIME_SLICE_NS);
+        let next_timer = run_queue.timers.peek().map(|timer| timer.deadline);
+        let deadline = slice_end.into_iter().chain(next_timer).min();
+        run_queue.timer_deadline = deadline.unwrap_or(u64::MAX);
+        apic::set_timer_deadline(deadline);
+        drop(run_queue);
 
-        let kernel_address = next_thread.kernel_stack.end_address();
-        CPUS.write().get_mut(lapic_id).set_ring0_rsp(kernel_address);
+        for runnable in migrating {
+            self.enqueue(self.place(runnable.affinity, None), runnable);
+        }
+        reaper::hand_over(reapable);
+
+        let next_thread = current_thread.upgrade().unwrap();
+        let mut next_thread = next_thread.write();
+
+        // A killed thread preempted in user mode never reaches the end of a
+        // syscall, so send it to the exit path instead.
+        if next_thread.killed && next_thread.context.is_user() {
+            next_thread.redirect_to_exit();
+        }
+
+        let cpu = current_cpu();
+        // Boot threads have no kernel stack, but never leave ring 0 either.
+        if let Some(kernel_stack) = next_thread.kernel_stack.as_ref() {
+            cpu.set_ring0_rsp(kernel_stack.end_address());
+        }
+        cpu.set_switched_out(switched_out.as_deref());
+        let page_table = next_thread.context.page_table_address();
+        cpu.set_active_page_table(page_table);
+        let cr3 = cpu.pcids().cr3_for(page_table);
+        next_thread.context.set_cr3(cr3);
 
         next_thread.context.address()
     }
@@ -1,41 +1,100 @@
-use alloc::boxed::Box;
-use x86_64::structures::paging::OffsetPageTable;
+use x86_64::structures::paging::Translate;
 use x86_64::VirtAddr;
 
-use crate::memory::{MappingType, MemoryManager};
+use super::ThreadId;
+use crate::error::RcResult;
+use crate::memory::{ref_current_page_table, FrameUsage, KernelArea, MappingType};
+use crate::memory::{MmuFlags, Vma, VmaKind, VmaList, GUARD_SIZE, KERNEL_STACKS};
 
 const KERNEL_STACK_SIZE: usize = 64 * 1024;
+
+/// Kernel stacks all have the same size, so the stack above a guard page is
+/// found from the address alone.
+const KERNEL_STACK_SLOT_SIZE: u64 = KERNEL_STACK_SIZE as u64 + GUARD_SIZE;
 const USER_STACK_END: usize = 0x7ffffefff000;
 const USER_STACK_SIZE: usize = 256 * 1024;
+const USER_STACK_INITIAL_SIZE: usize = 16 * 1024;
+const USER_STACK_GAP: usize = 4096;
 
-pub struct KernelStack(Box<[u8]>);
+pub struct KernelStack(KernelArea);
 
 impl KernelStack {
-    pub fn new() -> Self {
-        Self(Box::from(alloc::vec![0; KERNEL_STACK_SIZE]))
+    /// The owner's id is kept in the top word of the stack, where fault
+    /// handlers can read it without taking any lock.
+    pub fn new(owner: ThreadId) -> RcResult<Self> {
+        let flags = MappingType::KernelData.flags();
+        let area = KernelArea::new_in(
+            &KERNEL_STACKS,
+            KERNEL_STACK_SIZE,
+            flags,
+            FrameUsage::KernelStack,
+        )?;
+        unsafe {
+            (area.end_address() - 8u64)
+                .as_mut_ptr::<u64>()
+                .write(owner.0)
+        };
+        Ok(Self(area))
     }
 
     pub fn end_address(&self) -> VirtAddr {
-        VirtAddr::new(self.0.as_ptr_range().end as u64)
+        self.0.end_address() - 16u64
+    }
+
+    /// The thread whose kernel stack ran into the guard page at `address`.
+    pub fn overflowed_thread(address: VirtAddr) -> Option<ThreadId> {
+        let region_start = KERNEL_STACKS.start_address();
+        let region_size = KERNEL_STACKS.end_address() - region_start;
+
+        // Offset of the page above the guard, the start of the slot.
+        let offset =
+            (address.as_u64().checked_add(GUARD_SIZE)?).checked_sub(region_start.as_u64())?;
+        if offset >= region_size || offset % KERNEL_STACK_SLOT_SIZE >= GUARD_SIZE {
+            return None;
+        }
+        let slot_start = region_start + offset / KERNEL_STACK_SLOT_SIZE * KERNEL_STACK_SLOT_SIZE;
+        let owner_address = slot_start + KERNEL_STACK_SIZE as u64 - 8u64;
+
+        let page_table = unsafe { ref_current_page_table() };
+        page_table.translate_addr(owner_address)?;
+        Some(ThreadId(unsafe { owner_address.as_ptr::<u64>().read() }))
     }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct UserStack {
+    /// The slot of the stack, given back to the process when its thread
+    /// exits.
+    pub index: usize,
     pub end_address: VirtAddr,
 }
 
 impl UserStack {
-    pub fn new(page_table: &mut OffsetPageTable<'static>) -> Self {
-        let end_address = VirtAddr::new(USER_STACK_END as u64);
-
-        MemoryManager::alloc_range(
-            end_address - USER_STACK_SIZE as u64,
-            USER_STACK_SIZE as u64,
-            MappingType::UserData.flags(),
-            page_table,
-        )
-        .unwrap();
-
-        Self { end_address }
+    /// Stacks of the threads of a process are laid out downwards from
+    /// `USER_STACK_END`, separated by an unmapped page. They start small and
+    /// grow on faults up to `USER_STACK_SIZE`.
+    pub fn new(index: usize, vmas: &mut VmaList) -> RcResult<Self> {
+        let offset = index * (USER_STACK_SIZE + USER_STACK_GAP);
+        let end_address = VirtAddr::new((USER_STACK_END - offset) as u64);
+
+        vmas.insert(
+            Vma::new(
+                end_address - USER_STACK_INITIAL_SIZE as u64,
+                end_address,
+                MappingType::UserData.flags(),
+                VmaKind::Stack {
+                    limit: end_address - USER_STACK_SIZE as u64,
+                },
+            )
+            // Stacks never become executable.
+            .with_max_permissions(MmuFlags::READ | MmuFlags::WRITE),
+        )?;
+
+        Ok(Self { index, end_address })
+    }
+
+    /// The lowest address the stack may grow down to.
+    pub fn limit(&self) -> VirtAddr {
+        self.end_address - USER_STACK_SIZE as u64
     }
 }
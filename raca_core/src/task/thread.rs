@@ -1,21 +1,27 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use crate::{
-    arch::gdt::Selectors,
-    memory::{ExtendedPageTable, KERNEL_PAGE_TABLE},
+    arch::{gdt::Selectors, smp::current_cpu},
+    error::{RcError, RcResult},
+    memory::{ExtendedPageTable, SlabAllocator, SlabBox, TlbBatch, KERNEL_PAGE_TABLE},
+    object::{KObjectBase, KernelObject, ObjectType, Signals},
 };
 
-use super::{context::Context, process::KERNEL_PROCESS, stack::UserStack, SCHEDULER};
-use super::{process::WeakSharedProcess, stack::KernelStack};
+use super::{context::Context, process::KERNEL_PROCESS, stack::UserStack, CpuMask, SCHEDULER};
+use super::{current_thread, yield_now};
+use super::{policy::Priority, process::WeakSharedProcess, stack::KernelStack};
 use alloc::{
     boxed::Box,
     sync::{Arc, Weak},
 };
 use spin::RwLock;
 use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
 
-pub(super) type SharedThread = Arc<RwLock<Box<Thread>>>;
-pub(super) type WeakSharedThread = Weak<RwLock<Box<Thread>>>;
+pub type SharedThread = Arc<RwLock<SlabBox<Thread>>>;
+pub type WeakSharedThread = Weak<RwLock<SlabBox<Thread>>>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ThreadId(pub u64);
@@ -27,53 +33,153 @@ impl ThreadId {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Blocked,
+    Exited,
+}
+
 pub struct Thread {
     pub id: ThreadId,
-    pub kernel_stack: KernelStack,
+    pub state: ThreadState,
+    /// Freed by the reaper once the thread has exited and no CPU runs on
+    /// it anymore, even if handles keep the thread itself around. Boot
+    /// threads have none, they run on the stack they booted with.
+    pub kernel_stack: Option<KernelStack>,
+    /// Kernel threads have none.
+    pub user_stack: Option<UserStack>,
     pub context: Context,
     pub process: WeakSharedProcess,
+    pub base: KObjectBase,
+    /// Set when the process is killed; the thread exits as soon as it
+    /// reaches user mode or the end of a syscall.
+    pub killed: bool,
+    /// The CPU whose run queue holds the thread, or that last ran it.
+    pub cpu: u32,
+    /// Whether `cpu` is running the thread right now. Such a thread goes
+    /// back to a run queue when switched out, not when woken.
+    pub running: bool,
+    /// Set while a CPU uses the kernel stack of the thread, from switching
+    /// to it until right after switching away. Shared with `Runnable` so
+    /// that run queues can check it without locking the thread.
+    pub on_cpu: Arc<AtomicBool>,
+    /// Takes effect the next time the thread is queued.
+    pub priority: Priority,
+    /// Time run so far, see `Runnable`.
+    pub virtual_runtime: u64,
+    /// When a blocked thread wakes up by itself, in HPET nanoseconds.
+    pub sleep_deadline: Option<u64>,
+    pub affinity: CpuMask,
 }
 
 impl Thread {
-    pub(self) fn new(process: WeakSharedProcess) -> Self {
+    pub(self) fn new(process: WeakSharedProcess) -> RcResult<Self> {
+        let mut thread = Self::without_stack(process);
+        thread.kernel_stack = Some(KernelStack::new(thread.id)?);
+        Ok(thread)
+    }
+
+    fn without_stack(process: WeakSharedProcess) -> Self {
         Thread {
             id: ThreadId::new(),
-            kernel_stack: KernelStack::new(),
+            state: ThreadState::Ready,
+            kernel_stack: None,
+            user_stack: None,
             context: Context::default(),
             process,
+            base: KObjectBase::new(),
+            killed: false,
+            cpu: 0,
+            running: false,
+            on_cpu: Arc::new(AtomicBool::new(false)),
+            priority: Priority::DEFAULT,
+            virtual_runtime: 0,
+            sleep_deadline: None,
+            affinity: CpuMask::ALL,
         }
     }
 
-    pub fn get_init_thread() -> WeakSharedThread {
-        let thread = Self::new(Arc::downgrade(&KERNEL_PROCESS));
-        let thread = Arc::new(RwLock::new(Box::new(thread)));
+    /// The thread standing for the boot code of `cpu`, which keeps running
+    /// on the stack it booted with until it exits.
+    pub fn new_boot_thread(cpu: u32) -> WeakSharedThread {
+        let mut thread = Self::without_stack(Arc::downgrade(&KERNEL_PROCESS));
+        thread.cpu = cpu;
+        thread.running = true;
+        thread.on_cpu.store(true, Ordering::Relaxed);
+        // CPUs without a bit in the mask cannot be pinned to.
+        thread.affinity = CpuMask::single(cpu).unwrap_or(CpuMask::ALL);
+        let thread = Arc::new(RwLock::new(Box::new_in(thread, SlabAllocator)));
         KERNEL_PROCESS.write().threads.push(thread.clone());
         Arc::downgrade(&thread)
     }
 
-    pub fn new_kernel_thread(function: fn()) {
-        let mut thread = Self::new(Arc::downgrade(&KERNEL_PROCESS));
+    /// The thread `cpu` halts in when it has nothing else to run. It is
+    /// never queued, the scheduler switches to it directly.
+    pub fn new_idle_thread(cpu: u32) -> SharedThread {
+        let mut thread =
+            Self::new(Arc::downgrade(&KERNEL_PROCESS)).expect("Failed to create idle thread");
+        thread.cpu = cpu;
+        thread.priority = Priority::IDLE;
+        thread.affinity = CpuMask::single(cpu).unwrap_or(CpuMask::ALL);
+        thread.context.init(
+            idle as *const () as usize,
+            thread.kernel_stack_end(),
+            KERNEL_PAGE_TABLE.lock().physical_address(),
+            Selectors::get_kernel_segments(),
+        );
+
+        let thread = Arc::new(RwLock::new(Box::new_in(thread, SlabAllocator)));
+        KERNEL_PROCESS.write().threads.push(thread.clone());
+        thread
+    }
+
+    /// Starts `function` in a kernel thread, only ever scheduled on the CPUs
+    /// in `affinity`, e.g. `CpuMask::single(lapic_id)` to pin it. The thread
+    /// exits when `function` returns.
+    pub fn new_kernel_thread(function: fn(), priority: Priority, affinity: CpuMask) {
+        let mut thread =
+            Self::new(Arc::downgrade(&KERNEL_PROCESS)).expect("Failed to create kernel thread");
+        thread.priority = priority;
+        thread.affinity = affinity;
 
         thread.context.init(
-            function as usize,
-            thread.kernel_stack.end_address(),
+            run_kernel_thread as *const () as usize,
+            thread.kernel_stack_end(),
             KERNEL_PAGE_TABLE.lock().physical_address(),
             Selectors::get_kernel_segments(),
         );
+        thread.context.set_argument(function as usize);
 
-        let thread = Arc::new(RwLock::new(Box::new(thread)));
+        let thread = Arc::new(RwLock::new(Box::new_in(thread, SlabAllocator)));
         KERNEL_PROCESS.write().threads.push(thread.clone());
 
         interrupts::without_interrupts(|| {
-            SCHEDULER.lock().add(Arc::downgrade(&thread));
+            SCHEDULER.add(Arc::downgrade(&thread));
         });
     }
 
-    pub fn new_user_thread(process: WeakSharedProcess, entry_point: usize) {
-        let mut thread = Self::new(process.clone());
-        let process = process.upgrade().unwrap();
+    pub fn new_user_thread(
+        process: WeakSharedProcess,
+        entry_point: usize,
+        argument: usize,
+    ) -> RcResult<SharedThread> {
+        let mut thread = Self::new(process.clone())?;
+        let process = process.upgrade().ok_or(RcError::BAD_STATE)?;
         let mut process = process.write();
-        let user_stack = UserStack::new(&mut process.page_table);
+        if process.is_exiting() {
+            return Err(RcError::BAD_STATE);
+        }
+
+        let index = process.allocate_user_stack();
+        let user_stack = match UserStack::new(index, &mut process.vmas) {
+            Ok(user_stack) => user_stack,
+            Err(err) => {
+                process.free_user_stacks.push(index);
+                return Err(err);
+            }
+        };
+        thread.user_stack = Some(user_stack);
 
         thread.context.init(
             entry_point,
@@ -81,10 +187,138 @@ impl Thread {
             process.page_table.physical_address(),
             Selectors::get_user_segments(),
         );
+        thread.context.set_argument(argument);
 
-        let thread = Arc::new(RwLock::new(Box::new(thread)));
+        let thread = Arc::new(RwLock::new(Box::new_in(thread, SlabAllocator)));
         process.threads.push(thread.clone());
 
-        SCHEDULER.lock().add(Arc::downgrade(&thread));
+        SCHEDULER.add(Arc::downgrade(&thread));
+        Ok(thread)
+    }
+
+    /// Adds the only thread of a forked process, resuming from `context`.
+    /// It inherits the priority and affinity of the calling thread.
+    pub fn new_forked_thread(
+        process: WeakSharedProcess,
+        mut context: Context,
+    ) -> RcResult<SharedThread> {
+        let mut thread = Self::new(process.clone())?;
+        let current = current_thread();
+        thread.priority = current.read().priority;
+        thread.affinity = current.read().affinity;
+        // The child resumes on the same stack, copied with the rest.
+        thread.user_stack = current.read().user_stack;
+        let process = process.upgrade().ok_or(RcError::BAD_STATE)?;
+        let mut process = process.write();
+
+        context.set_page_table_address(process.page_table.physical_address());
+        thread.context = context;
+
+        let thread = Arc::new(RwLock::new(Box::new_in(thread, SlabAllocator)));
+        process.threads.push(thread.clone());
+
+        SCHEDULER.add(Arc::downgrade(&thread));
+        Ok(thread)
+    }
+
+    /// Makes a killed thread that was interrupted in user mode run
+    /// `exit_killed_thread` on its kernel stack the next time it is picked.
+    pub fn redirect_to_exit(&mut self) {
+        let page_table_address = self.context.page_table_address();
+        self.context.init(
+            exit_killed_thread as *const () as usize,
+            self.kernel_stack_end(),
+            page_table_address,
+            Selectors::get_kernel_segments(),
+        );
+    }
+
+    /// Where the kernel stack starts, growing down. Exited threads no
+    /// longer have one.
+    pub fn kernel_stack_end(&self) -> VirtAddr {
+        self.kernel_stack
+            .as_ref()
+            .expect("Thread has exited")
+            .end_address()
+    }
+
+    /// Terminates the calling thread. When it is the last thread of its
+    /// process, the process terminates as well.
+    pub fn exit_current() -> ! {
+        interrupts::disable();
+
+        // The address space may be freed below, keep running on the kernel's.
+        let kernel_page_table = KERNEL_PAGE_TABLE.lock().physical_address();
+        current_cpu().set_active_page_table(kernel_page_table);
+        unsafe {
+            Cr3::write(
+                PhysFrame::containing_address(kernel_page_table),
+                Cr3Flags::empty(),
+            )
+        };
+
+        let thread = current_thread();
+        if let Some(process) = thread.read().process.upgrade() {
+            // Flushed once the process is unlocked.
+            let mut batch = TlbBatch::new();
+            let handles = {
+                let mut process = process.write();
+                if let Some(user_stack) = thread.write().user_stack.take() {
+                    process.free_user_stack(user_stack, &mut batch);
+                }
+                process.threads.retain(|other| !Arc::ptr_eq(other, &thread));
+                process.threads.is_empty().then(|| process.terminate())
+            };
+            batch.flush();
+
+            if let Some(handles) = handles {
+                drop(handles);
+                process.read().exit_process();
+                process.read().base.signal_set(Signals::TERMINATED);
+            }
+        }
+
+        let base = thread.read().base.clone();
+        base.signal_set(Signals::TERMINATED);
+
+        SCHEDULER.exit_current();
+        drop((thread, base));
+
+        yield_now();
+        unreachable!("Exited thread was scheduled again");
+    }
+}
+
+extern "C" fn exit_killed_thread() -> ! {
+    Thread::exit_current()
+}
+
+/// Where kernel threads start, so that their function can return.
+extern "C" fn run_kernel_thread(function: usize) -> ! {
+    let function: fn() = unsafe { core::mem::transmute(function) };
+    function();
+    Thread::exit_current()
+}
+
+extern "C" fn idle() -> ! {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Called on the way back to user space from a syscall.
+pub fn exit_if_killed() {
+    if current_thread().read().killed {
+        Thread::exit_current();
+    }
+}
+
+impl KernelObject for RwLock<SlabBox<Thread>> {
+    fn base(&self) -> KObjectBase {
+        self.read().base.clone()
+    }
+
+    fn object_type(&self) -> ObjectType {
+        ObjectType::Thread
     }
 }
@@ -0,0 +1,112 @@
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{current_thread, yield_now, SharedThread, WeakSharedThread, SCHEDULER};
+use crate::device::hpet::HPET;
+use crate::error::{RcError, RcResult};
+
+/// A set of threads parked until someone wakes them.
+pub struct WaitQueue {
+    threads: Mutex<Vec<WeakSharedThread>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn wake_one(&self) {
+        let thread = interrupts::without_interrupts(|| {
+            let mut threads = self.threads.lock();
+            (!threads.is_empty()).then(|| threads.remove(0))
+        });
+        if let Some(thread) = thread.and_then(|thread| thread.upgrade()) {
+            interrupts::without_interrupts(|| SCHEDULER.wake(&thread));
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let threads = interrupts::without_interrupts(|| core::mem::take(&mut *self.threads.lock()));
+        if threads.is_empty() {
+            return;
+        }
+
+        interrupts::without_interrupts(|| {
+            for thread in threads.iter().filter_map(|thread| thread.upgrade()) {
+                SCHEDULER.wake(&thread);
+            }
+        });
+    }
+
+    fn register(&self, thread: &SharedThread) {
+        self.threads.lock().push(Arc::downgrade(thread));
+    }
+
+    fn unregister(&self, thread: &SharedThread) {
+        let thread = Arc::downgrade(thread);
+        self.threads.lock().retain(|other| !other.ptr_eq(&thread));
+    }
+}
+
+/// Parks the current thread on `queues` until `condition` yields a value.
+///
+/// The condition is re-evaluated every time one of the queues is woken.
+/// With a `deadline` (in HPET nanoseconds) the wait gives up with
+/// `TIMED_OUT` once it has passed.
+pub fn wait_until<T>(
+    queues: &[&WaitQueue],
+    deadline: Option<u64>,
+    mut condition: impl FnMut() -> Option<T>,
+) -> RcResult<T> {
+    let interrupts_enabled = interrupts::are_enabled();
+    interrupts::disable();
+
+    let thread = current_thread();
+    let result = loop {
+        // Mark ourselves blocked and register before checking, so that a
+        // wakeup racing with the check is never lost.
+        SCHEDULER.block_current(deadline);
+        queues.iter().for_each(|queue| queue.register(&thread));
+
+        // A killed thread gives up waiting and exits on its way out of the
+        // syscall.
+        let result = if thread.read().killed {
+            Some(Err(RcError::BAD_STATE))
+        } else {
+            match condition() {
+                Some(value) => Some(Ok(value)),
+                None if deadline.is_some_and(|deadline| HPET.elapsed_ns() >= deadline) => {
+                    Some(Err(RcError::TIMED_OUT))
+                }
+                None => None,
+            }
+        };
+
+        if let Some(result) = result {
+            SCHEDULER.wake(&thread);
+            break result;
+        }
+
+        // Comes back once woken, other threads or the idle thread run
+        // meanwhile.
+        yield_now();
+        queues.iter().for_each(|queue| queue.unregister(&thread));
+    };
+    queues.iter().for_each(|queue| queue.unregister(&thread));
+
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    result
+}
+
+/// Blocks the current thread until `deadline` (in HPET nanoseconds) passes.
+pub fn sleep_until(deadline: u64) -> RcResult<()> {
+    match wait_until(&[], Some(deadline), || None::<()>) {
+        Err(RcError::TIMED_OUT) => Ok(()),
+        result => result,
+    }
+}